@@ -0,0 +1,196 @@
+/*
+注册用户
+    - /register <password> 把当前用户名注册为账号，之后用这个用户名登录需要密码
+    - 未读的 @ 提及按用户保存，注册用户的会持久化，访客的只保存在内存中，断开后清除
+    - 密码用 Argon2id 加盐哈希后保存，旧版本的 blake3 哈希在下一次登录成功后升级
+    - 整个文件在每次修改后重写
+*/
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::ChatError,
+    storage::{load_json, save_json},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Account {
+    /// PHC 格式的 Argon2id 哈希，旧版本的账号是 blake3(salt‖password) 的十六进制
+    password_hash: String,
+    /// 只有旧版本的账号有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    #[serde(default)]
+    unread_mentions: Vec<u64>,
+}
+
+/// 保存的密码，登录时在锁外面校验
+#[derive(Debug, Clone)]
+pub struct StoredPassword {
+    hash: String,
+    legacy_salt: Option<String>,
+}
+
+impl StoredPassword {
+    /// 旧格式的哈希需要重新计算
+    pub fn is_legacy(&self) -> bool {
+        self.legacy_salt.is_some()
+    }
+}
+
+#[derive(Debug)]
+pub struct Accounts {
+    path: PathBuf,
+    accounts: BTreeMap<String, Account>,
+    guest_mentions: HashMap<String, Vec<u64>>,
+}
+
+impl Accounts {
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let accounts = load_json(&path).await?;
+        Ok(Self {
+            path,
+            accounts,
+            guest_mentions: HashMap::new(),
+        })
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.accounts.contains_key(username)
+    }
+
+    /// `password_hash` 是 [`hash_password`] 的结果
+    pub async fn register(
+        &mut self,
+        username: &str,
+        password_hash: String,
+    ) -> Result<(), ChatError> {
+        if self.is_registered(username) {
+            return Err(ChatError::AlreadyRegistered(username.to_string()));
+        }
+        let account = Account {
+            password_hash,
+            salt: None,
+            unread_mentions: self.guest_mentions.remove(username).unwrap_or_default(),
+        };
+        self.accounts.insert(username.to_string(), account);
+        self.save().await
+    }
+
+    pub fn password(&self, username: &str) -> Option<StoredPassword> {
+        self.accounts.get(username).map(|account| StoredPassword {
+            hash: account.password_hash.clone(),
+            legacy_salt: account.salt.clone(),
+        })
+    }
+
+    /// 把旧格式的哈希换成新的，`password_hash` 是 [`hash_password`] 的结果
+    pub async fn upgrade_password(
+        &mut self,
+        username: &str,
+        password_hash: String,
+    ) -> Result<(), ChatError> {
+        let Some(account) = self.accounts.get_mut(username) else {
+            return Ok(());
+        };
+        account.password_hash = password_hash;
+        account.salt = None;
+        self.save().await
+    }
+
+    pub async fn add_mention(&mut self, username: &str, id: u64) -> Result<(), ChatError> {
+        match self.accounts.get_mut(username) {
+            Some(account) => {
+                account.unread_mentions.push(id);
+                self.save().await
+            }
+            None => {
+                self.guest_mentions
+                    .entry(username.to_string())
+                    .or_default()
+                    .push(id);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn unread_mentions(&self, username: &str) -> usize {
+        match self.accounts.get(username) {
+            Some(account) => account.unread_mentions.len(),
+            None => self.guest_mentions.get(username).map_or(0, Vec::len),
+        }
+    }
+
+    /// 取出并清空未读的提及
+    pub async fn take_mentions(&mut self, username: &str) -> Result<Vec<u64>, ChatError> {
+        match self.accounts.get_mut(username) {
+            Some(account) => {
+                let mentions = std::mem::take(&mut account.unread_mentions);
+                if !mentions.is_empty() {
+                    self.save().await?;
+                }
+                Ok(mentions)
+            }
+            None => Ok(self.guest_mentions.remove(username).unwrap_or_default()),
+        }
+    }
+
+    /// 访客断开后不再保留任何状态
+    pub fn forget_guest(&mut self, username: &str) {
+        self.guest_mentions.remove(username);
+    }
+
+    async fn save(&self) -> Result<(), ChatError> {
+        save_json(&self.path, &self.accounts).await
+    }
+}
+
+/// Argon2 故意很慢，在阻塞线程中计算
+pub async fn hash_password(password: &str) -> Result<String, ChatError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| ChatError::PasswordHash(e.to_string()))
+    })
+    .await
+    .map_err(|e| ChatError::PasswordHash(e.to_string()))?
+}
+
+pub async fn verify_password(stored: StoredPassword, password: &str) -> bool {
+    let password = password.to_string();
+    let verified = tokio::task::spawn_blocking(move || match &stored.legacy_salt {
+        Some(salt) => legacy_hash(salt, &password) == stored.hash,
+        None => PasswordHash::new(&stored.hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        }),
+    })
+    .await;
+    verified.unwrap_or(false)
+}
+
+/// 常数时间比较两个密钥，比如 operator 密码，比较的是 blake3 哈希，不会从耗时泄露相同的前缀
+pub fn same_secret(expected: &str, actual: &str) -> bool {
+    blake3::hash(expected.as_bytes()) == blake3::hash(actual.as_bytes())
+}
+
+fn legacy_hash(salt: &str, password: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
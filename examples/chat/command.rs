@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use crate::{
+    error::ChatError,
+    export::{Format, Selection},
+    rooms::RoomChange,
+    search::SearchQuery,
+};
+
+const DEFAULT_HISTORY: usize = 20;
+
+/// 客户端发送的一行内容，以 `/` 开头的是命令，其余都是普通聊天消息
+#[derive(Debug)]
+pub enum Command {
+    Chat(String),
+    Reply {
+        parent: u64,
+        content: String,
+    },
+    Edit {
+        id: u64,
+        content: String,
+    },
+    Delete(u64),
+    History(usize),
+    Who,
+    Join {
+        room: String,
+        password: Option<String>,
+    },
+    Part(Option<String>),
+    List,
+    Topic(Option<String>),
+    Invite(String),
+    /// /op、/deop 和 /set 都是修改当前房间的设置
+    Configure(RoomChange),
+    Oper(String),
+    Register(String),
+    Mentions,
+    Msg {
+        to: String,
+        content: String,
+    },
+    Search(SearchQuery),
+    Compact,
+    Export {
+        format: Format,
+        selection: Selection,
+    },
+}
+
+impl FromStr for Command {
+    type Err = ChatError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = line.strip_prefix('/') else {
+            return Ok(Self::Chat(line.to_string()));
+        };
+        let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        let args = args.trim();
+        match name {
+            "reply" => {
+                let (parent, content) =
+                    id_and_text(args).ok_or(ChatError::Usage("/reply <id> <text>"))?;
+                Ok(Self::Reply { parent, content })
+            }
+            "edit" => {
+                let (id, content) =
+                    id_and_text(args).ok_or(ChatError::Usage("/edit <id> <text>"))?;
+                Ok(Self::Edit { id, content })
+            }
+            "delete" => {
+                let id = parse_id(args).ok_or(ChatError::Usage("/delete <id>"))?;
+                Ok(Self::Delete(id))
+            }
+            "history" if args.is_empty() => Ok(Self::History(DEFAULT_HISTORY)),
+            "history" => {
+                let n = args
+                    .parse()
+                    .map_err(|_| ChatError::Usage("/history [count]"))?;
+                Ok(Self::History(n))
+            }
+            "who" => Ok(Self::Who),
+            "join" if !args.is_empty() => {
+                let (room, password) = match args.split_once(' ') {
+                    Some((room, password)) => (room, Some(password.trim().to_string())),
+                    None => (args, None),
+                };
+                Ok(Self::Join {
+                    room: room.to_string(),
+                    password,
+                })
+            }
+            "join" => Err(ChatError::Usage("/join <room> [password]")),
+            "part" if args.is_empty() => Ok(Self::Part(None)),
+            "part" => Ok(Self::Part(Some(args.to_string()))),
+            "list" => Ok(Self::List),
+            "topic" if args.is_empty() => Ok(Self::Topic(None)),
+            "topic" => Ok(Self::Topic(Some(args.to_string()))),
+            "op" if !args.is_empty() => Ok(Self::Configure(RoomChange::Op(args.to_string()))),
+            "op" => Err(ChatError::Usage("/op <username>")),
+            "deop" if !args.is_empty() => Ok(Self::Configure(RoomChange::Deop(args.to_string()))),
+            "deop" => Err(ChatError::Usage("/deop <username>")),
+            "invite" if !args.is_empty() => Ok(Self::Invite(args.to_string())),
+            "invite" => Err(ChatError::Usage("/invite <username>")),
+            "set" => {
+                let (name, value) = args.split_once(' ').unwrap_or((args, ""));
+                RoomChange::parse_setting(name, value.trim())
+                    .map(Self::Configure)
+                    .ok_or(ChatError::Usage(
+                        "/set <invite-only|announce> <on|off>, /set <password|max-members> <value|off>",
+                    ))
+            }
+            "oper" if !args.is_empty() => Ok(Self::Oper(args.to_string())),
+            "oper" => Err(ChatError::Usage("/oper <password>")),
+            "register" if !args.is_empty() => Ok(Self::Register(args.to_string())),
+            "register" => Err(ChatError::Usage("/register <password>")),
+            "mentions" => Ok(Self::Mentions),
+            "msg" => match args.split_once(' ') {
+                Some((to, content)) if !content.trim().is_empty() => Ok(Self::Msg {
+                    to: to.to_string(),
+                    content: content.trim().to_string(),
+                }),
+                _ => Err(ChatError::Usage("/msg <username> <text>")),
+            },
+            "search" => Ok(Self::Search(SearchQuery::parse(args)?)),
+            "compact" => Ok(Self::Compact),
+            "export" => {
+                let mut args = args.split_whitespace();
+                let format = args
+                    .next()
+                    .ok_or(ChatError::Usage(
+                        "/export <jsonl|md|html> [room] [from..to]",
+                    ))?
+                    .parse()?;
+                Ok(Self::Export {
+                    format,
+                    selection: Selection::parse(args)?,
+                })
+            }
+            _ => Err(ChatError::UnknownCommand(name.to_string())),
+        }
+    }
+}
+
+/// 消息 ID 可以写成 `12` 或者 `#12`
+pub fn parse_id(s: &str) -> Option<u64> {
+    s.strip_prefix('#').unwrap_or(s).parse().ok()
+}
+
+fn id_and_text(args: &str) -> Option<(u64, String)> {
+    let (id, text) = args.split_once(' ')?;
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    Some((parse_id(id)?, text.to_string()))
+}
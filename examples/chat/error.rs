@@ -0,0 +1,62 @@
+use thiserror::Error;
+
+/// 命令执行失败的原因，会以 notice 的形式回复给发送命令的用户
+#[derive(Error, Debug)]
+pub enum ChatError {
+    #[error("unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("message #{0} not found")]
+    NotFound(u64),
+    #[error("message #{0} has been deleted")]
+    Deleted(u64),
+    #[error("message #{0} is not yours")]
+    NotAuthor(u64),
+    #[error("wrong operator password")]
+    WrongPassword,
+    #[error("only operators can do that, use /oper <password> first")]
+    NotOperator,
+    #[error("{0} is already registered")]
+    AlreadyRegistered(String),
+    #[error("{0} is not online and not registered")]
+    UserNotFound(String),
+    #[error("mailbox of {0} is full")]
+    MailboxFull(String),
+    #[error("can not send a direct message to yourself")]
+    SelfMessage,
+    #[error("{0}")]
+    InvalidUsername(String),
+    #[error("invalid room name: {0}")]
+    InvalidRoom(String),
+    #[error("room {0} does not exist")]
+    NoSuchRoom(String),
+    #[error("you are not in {0}")]
+    NotInRoom(String),
+    #[error("you are not in any room, use /join <room> first")]
+    NoRoom,
+    #[error("{0} is invite-only")]
+    InviteOnly(String),
+    #[error("wrong password for {0}")]
+    BadRoomPassword(String),
+    #[error("{0} is full")]
+    RoomFull(String),
+    #[error("you are not an operator of {0}")]
+    NotRoomOperator(String),
+    #[error("only the owner of {0} can do that")]
+    NotRoomOwner(String),
+    #[error("{0} is in announce mode, only operators can post")]
+    ReadOnlyRoom(String),
+    #[error("your message to {0} was blocked by the content filter")]
+    Blocked(String),
+    #[error("message is empty")]
+    EmptyMessage,
+    #[error("{0}")]
+    InvalidExport(String),
+    #[error("failed to hash password: {0}")]
+    PasswordHash(String),
+    #[error("storage error: {0}")]
+    Storage(#[from] std::io::Error),
+    #[error("failed to encode data: {0}")]
+    Encode(#[from] serde_json::Error),
+}
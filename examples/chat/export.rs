@@ -0,0 +1,254 @@
+/*
+导出聊天记录
+    - 可以按房间、时间范围或者消息 ID 范围筛选
+    - 输出 JSON lines、Markdown 或者不依赖外部资源的 HTML，都带有时间和作者
+    - operator 用 /export 导出到数据目录，也可以用 `chat export` 子命令直接读取磁盘上的日志
+*/
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::{fmt::Write, str::FromStr};
+
+use crate::{command::parse_id, error::ChatError, history::ChatRecord, state::normalize_room};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Markdown,
+    Html,
+}
+
+/// 筛选条件，时间范围包含开始不包含结束，ID 范围两端都包含
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub room: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::JsonLines => "jsonl",
+            Self::Markdown => "md",
+            Self::Html => "html",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "md" | "markdown" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => Err(ChatError::InvalidExport(format!("unknown format {s}"))),
+        }
+    }
+}
+
+impl Selection {
+    /// 每个参数是一个房间名，或者 `from..to` 形式的范围，两端都可以省略
+    /// 范围的两端是消息 ID（`#12`）或者时间（`2024-05-01`、`2024-05-01T10:00`、RFC 3339）
+    pub fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Result<Self, ChatError> {
+        let mut selection = Self::default();
+        for arg in args {
+            let Some((from, to)) = arg.split_once("..") else {
+                selection.room = Some(normalize_room(arg)?);
+                continue;
+            };
+            let is_id = |s: &str| s.is_empty() || parse_id(s).is_some();
+            if is_id(from) && is_id(to) {
+                selection.first = parse_id(from);
+                selection.last = parse_id(to);
+            } else {
+                selection.since = parse_time(from)?;
+                selection.until = parse_time(to)?;
+            }
+        }
+        Ok(selection)
+    }
+
+    pub fn matches(&self, record: &ChatRecord) -> bool {
+        self.room.as_ref().is_none_or(|room| *room == record.room)
+            && self.since.is_none_or(|since| record.posted_at >= since)
+            && self.until.is_none_or(|until| record.posted_at < until)
+            && self.first.is_none_or(|first| record.id >= first)
+            && self.last.is_none_or(|last| record.id <= last)
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = vec![self.room.clone().unwrap_or_else(|| "all rooms".to_string())];
+        if self.since.is_some() || self.until.is_some() {
+            let time = |t: Option<DateTime<Utc>>| t.map(|t| t.format(TIME_FORMAT).to_string());
+            parts.push(format!(
+                "from {} to {}",
+                time(self.since).unwrap_or_else(|| "the beginning".to_string()),
+                time(self.until).unwrap_or_else(|| "now".to_string())
+            ));
+        }
+        if self.first.is_some() || self.last.is_some() {
+            let id = |id: Option<u64>| id.map_or_else(String::new, |id| format!("#{id}"));
+            parts.push(format!("messages {}..{}", id(self.first), id(self.last)));
+        }
+        parts.join(", ")
+    }
+}
+
+/// 按格式渲染选中的消息，已删除的消息保留占位
+pub fn export<'a>(
+    records: impl IntoIterator<Item = &'a ChatRecord>,
+    selection: &Selection,
+    format: Format,
+) -> Result<String, ChatError> {
+    let records: Vec<&ChatRecord> = records
+        .into_iter()
+        .filter(|record| selection.matches(record))
+        .collect();
+    match format {
+        Format::JsonLines => {
+            let mut out = String::new();
+            for record in records {
+                out.push_str(&serde_json::to_string(record)?);
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        Format::Markdown => Ok(markdown(&records, selection)),
+        Format::Html => Ok(html(&records, selection)),
+    }
+}
+
+fn markdown(records: &[&ChatRecord], selection: &Selection) -> String {
+    let mut out = format!("# Chat transcript: {}\n\n", selection.describe());
+    let _ = writeln!(
+        out,
+        "Exported at {}, {} messages.\n",
+        Utc::now().format(TIME_FORMAT),
+        records.len()
+    );
+    for record in records {
+        let _ = write!(
+            out,
+            "- `{}` **{}** {} #{}",
+            record.posted_at.format(TIME_FORMAT),
+            record.sender,
+            record.room,
+            record.id
+        );
+        if let Some(parent) = record.reply_to {
+            let _ = write!(out, " (re #{parent})");
+        }
+        match &record.deleted_by {
+            Some(by) => {
+                let _ = writeln!(out, ": _deleted by {by}_");
+            }
+            None => {
+                let _ = write!(out, ": {}", record.content);
+                if let Some(at) = record.edited_at {
+                    let _ = write!(out, " _(edited {})_", at.format(TIME_FORMAT));
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn html(records: &[&ChatRecord], selection: &Selection) -> String {
+    let title = escape(&format!("Chat transcript: {}", selection.describe()));
+    let mut out = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 960px; margin: 2em auto; color: #222; }}
+table {{ border-collapse: collapse; width: 100%; }}
+td {{ padding: 4px 8px; border-bottom: 1px solid #eee; vertical-align: top; }}
+.time, .id {{ color: #888; white-space: nowrap; font-family: monospace; }}
+.sender {{ font-weight: bold; white-space: nowrap; }}
+.deleted, .edited {{ color: #888; font-style: italic; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p>Exported at {}, {} messages.</p>
+<table>
+"#,
+        Utc::now().format(TIME_FORMAT),
+        records.len()
+    );
+    for record in records {
+        let _ = write!(
+            out,
+            r#"<tr id="m{id}"><td class="time">{}</td><td class="id">{} #{id}</td><td class="sender">{}</td><td>"#,
+            record.posted_at.format(TIME_FORMAT),
+            escape(&record.room),
+            escape(&record.sender),
+            id = record.id
+        );
+        if let Some(parent) = record.reply_to {
+            let _ = write!(out, r##"<a href="#m{parent}">re #{parent}</a> "##);
+        }
+        match &record.deleted_by {
+            Some(by) => {
+                let _ = write!(
+                    out,
+                    r#"<span class="deleted">deleted by {}</span>"#,
+                    escape(by)
+                );
+            }
+            None => {
+                out.push_str(&escape(&record.content));
+                if let Some(at) = record.edited_at {
+                    let _ = write!(
+                        out,
+                        r#" <span class="edited">(edited {})</span>"#,
+                        at.format(TIME_FORMAT)
+                    );
+                }
+            }
+        }
+        out.push_str("</td></tr>\n");
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn parse_time(s: &str) -> Result<Option<DateTime<Utc>>, ChatError> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M") {
+        return Ok(Some(time.and_utc()));
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_hms_opt(0, 0, 0).map(|time| time.and_utc())),
+        Err(_) => Err(ChatError::InvalidExport(format!("invalid time {s}"))),
+    }
+}
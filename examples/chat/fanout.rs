@@ -0,0 +1,225 @@
+/*
+消息分发
+    - queue：每个 peer 一个有界 mpsc 队列，广播时逐个发送，慢的 client 队列满了会拖慢发送者
+    - broadcast：所有 peer 共享一个 tokio broadcast channel（环形缓冲区），每条消息带上收件人，
+      每个 peer 从自己的位置读取并跳过不是发给自己的消息，发送者从不等待
+    - broadcast 模式下读得太慢的 peer 会落后超过缓冲区大小，跳过丢失的消息并收到提示
+    - broadcast 模式下断开的通知不经过环形缓冲区，每个 peer 一个 oneshot，落后的 peer 也不会错过
+    - 两种方式都保证同一个 peer 收到的消息和发送顺序一致，用 chat_bench 对比
+    - 单机测试（release，发送 10 秒）：
+        - 500 个 client，50 条/秒：两者差不多，queue 的尾延迟更低（p99 18ms 对 25ms）
+        - 2000 个 client，100 条/秒：queue 的 p50 2.3s、p99 7.0s，broadcast 的 p50 0.37s、p99 2.2s
+*/
+
+use dashmap::DashMap;
+use std::{collections::HashSet, sync::Arc};
+use tokio::sync::{
+    broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+    mpsc, oneshot,
+};
+use tracing::warn;
+
+use crate::{
+    config::{Delivery, FanoutKind},
+    message::Message,
+    state::PeerId,
+};
+
+#[derive(Debug)]
+pub enum Fanout {
+    Queue {
+        senders: DashMap<PeerId, mpsc::Sender<Arc<Message>>>,
+        capacity: usize,
+    },
+    Broadcast {
+        sender: broadcast::Sender<Envelope>,
+        /// drop 掉 peer 的 sender 通知它断开
+        closers: DashMap<PeerId, oneshot::Sender<()>>,
+    },
+}
+
+/// broadcast channel 中的一条消息
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    to: Recipients,
+    message: Arc<Message>,
+}
+
+#[derive(Debug, Clone)]
+enum Recipients {
+    One(PeerId),
+    /// 发送时的快照，之后加入的人收不到
+    Many(Arc<HashSet<PeerId>>),
+}
+
+/// 一个 peer 的收件箱，由前端读取并写回 client
+#[derive(Debug)]
+pub enum Inbox {
+    Queue(mpsc::Receiver<Arc<Message>>),
+    Broadcast {
+        peer_id: PeerId,
+        receiver: broadcast::Receiver<Envelope>,
+        closed: oneshot::Receiver<()>,
+        /// 收到断开的通知之后只读缓冲区中剩下的消息
+        closing: bool,
+    },
+}
+
+impl Fanout {
+    pub fn new(config: &Delivery) -> Self {
+        match config.fanout {
+            FanoutKind::Queue => Self::Queue {
+                senders: DashMap::new(),
+                capacity: config.queue_size,
+            },
+            FanoutKind::Broadcast => Self::Broadcast {
+                sender: broadcast::channel(config.fanout_buffer).0,
+                closers: DashMap::new(),
+            },
+        }
+    }
+
+    pub fn subscribe(&self, peer_id: PeerId) -> Inbox {
+        match self {
+            Self::Queue { senders, capacity } => {
+                let (tx, rx) = mpsc::channel(*capacity);
+                senders.insert(peer_id, tx);
+                Inbox::Queue(rx)
+            }
+            Self::Broadcast { sender, closers } => {
+                let (closer, closed) = oneshot::channel();
+                closers.insert(peer_id, closer);
+                Inbox::Broadcast {
+                    peer_id,
+                    receiver: sender.subscribe(),
+                    closed,
+                    closing: false,
+                }
+            }
+        }
+    }
+
+    /// 之后 peer 的收件箱在读完已有的消息后结束
+    pub fn unsubscribe(&self, peer_id: PeerId) {
+        match self {
+            Self::Queue { senders, .. } => {
+                senders.remove(&peer_id);
+            }
+            Self::Broadcast { closers, .. } => {
+                closers.remove(&peer_id);
+            }
+        }
+    }
+
+    pub async fn send(&self, peer_id: PeerId, message: Arc<Message>) {
+        match self {
+            Self::Queue { senders, .. } => {
+                let Some(sender) = senders.get(&peer_id).map(|sender| sender.clone()) else {
+                    return;
+                };
+                if let Err(e) = sender.send(message).await {
+                    warn!("Fail to send message to {peer_id};{e}");
+                }
+            }
+            Self::Broadcast { sender, .. } => {
+                //没有 receiver 时发送失败，忽略
+                let _ = sender.send(Envelope {
+                    to: Recipients::One(peer_id),
+                    message,
+                });
+            }
+        }
+    }
+
+    pub async fn send_all(
+        &self,
+        peer_ids: impl IntoIterator<Item = PeerId>,
+        message: Arc<Message>,
+    ) {
+        match self {
+            Self::Queue { senders, .. } => {
+                //先收集 sender 再发送，发送时不持有 DashMap 的锁
+                let targets: Vec<(PeerId, mpsc::Sender<Arc<Message>>)> = peer_ids
+                    .into_iter()
+                    .filter_map(|peer_id| {
+                        senders
+                            .get(&peer_id)
+                            .map(|sender| (peer_id, sender.clone()))
+                    })
+                    .collect();
+                for (peer_id, sender) in targets {
+                    if let Err(e) = sender.send(message.clone()).await {
+                        warn!("Fail to send message to {peer_id};{e}");
+                        //发送失败，不再给这个 peer 发消息
+                        senders.remove(&peer_id);
+                    }
+                }
+            }
+            Self::Broadcast { sender, .. } => {
+                let to: HashSet<PeerId> = peer_ids.into_iter().collect();
+                if to.is_empty() {
+                    return;
+                }
+                let _ = sender.send(Envelope {
+                    to: Recipients::Many(Arc::new(to)),
+                    message,
+                });
+            }
+        }
+    }
+}
+
+impl Inbox {
+    /// 返回 None 表示 peer 已经断开
+    pub async fn recv(&mut self) -> Option<Arc<Message>> {
+        match self {
+            Self::Queue(receiver) => receiver.recv().await,
+            Self::Broadcast {
+                peer_id,
+                receiver,
+                closed,
+                closing,
+            } => loop {
+                let received = if *closing {
+                    match receiver.try_recv() {
+                        Ok(envelope) => Ok(envelope),
+                        Err(TryRecvError::Lagged(skipped)) => Err(RecvError::Lagged(skipped)),
+                        Err(_) => return None,
+                    }
+                } else {
+                    tokio::select! {
+                        biased;
+                        _ = &mut *closed => {
+                            //断开之前已经在缓冲区中的消息仍然投递
+                            *closing = true;
+                            continue;
+                        }
+                        received = receiver.recv() => received,
+                    }
+                };
+                match received {
+                    Ok(envelope) if envelope.to.contains(peer_id) => return Some(envelope.message),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("{peer_id} lagged behind by {skipped} messages");
+                        let notice = "your connection is too slow, some messages were dropped";
+                        return Some(Arc::new(Message::notice(notice)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            },
+        }
+    }
+}
+
+impl Recipients {
+    fn contains(&self, peer_id: &PeerId) -> bool {
+        match self {
+            Self::One(to) => to == peer_id,
+            Self::Many(to) => to.contains(peer_id),
+        }
+    }
+}
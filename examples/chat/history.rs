@@ -0,0 +1,709 @@
+/*
+聊天记录
+    - 内存中保存每条消息的最新状态
+    - 磁盘上的日志只追加不修改：发送、编辑、删除都记录为一条事件，作为审计记录
+    - 日志分成多个日志段保存在数据目录的 messages/ 下，按编号顺序写入，当前日志段超过大小限制后开始写新的日志段
+    - 启动时按顺序重放所有日志段恢复内存状态，导出聊天记录时也只读地重放同一份日志
+    - 全文索引随内存状态一起更新
+    - 压缩：按房间的保留策略找出过期的消息，先从内存中删除，再重写已经写完的日志段，去掉这些消息的所有事件
+        - 每个日志段先写到临时文件并 fsync，再 rename 覆盖原文件，崩溃时每个日志段要么是旧的要么是新的
+        - 启动时删除上次崩溃留下的临时文件，没有重写完的过期消息在下一次压缩时再删除
+        - 重写之前在当前日志段记录下一个消息 ID，最新的消息过期后重启也不会重复使用 ID
+*/
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::Retention,
+    error::ChatError,
+    search::{Index, SearchQuery},
+};
+
+/// 引入房间之前默认房间的名字，和配置无关
+const LEGACY_ROOM: &str = "#general";
+const SEGMENT_EXTENSION: &str = "jsonl";
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LogEntry {
+    Posted {
+        id: u64,
+        at: DateTime<Utc>,
+        /// 引入房间之前的日志没有这个字段，都属于当时的默认房间
+        #[serde(default = "default_room")]
+        room: String,
+        sender: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<String>,
+    },
+    Edited {
+        id: u64,
+        at: DateTime<Utc>,
+        by: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<String>,
+    },
+    Deleted {
+        id: u64,
+        at: DateTime<Utc>,
+        by: String,
+    },
+    /// 压缩时写入当前日志段，过期的消息被删除后也不会重复使用它们的 ID
+    Compacted {
+        at: DateTime<Utc>,
+        next_id: u64,
+        expired: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatRecord {
+    pub id: u64,
+    pub room: String,
+    pub posted_at: DateTime<Utc>,
+    pub sender: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    records: BTreeMap<u64, ChatRecord>,
+    next_id: u64,
+    index: Index,
+    dir: PathBuf,
+    /// 正在追加的日志段
+    log: File,
+    segment: u64,
+    segment_len: u64,
+    segment_bytes: u64,
+}
+
+/// 一次压缩的结果
+#[derive(Debug, Default)]
+pub struct Compaction {
+    pub expired: usize,
+    pub rewritten: usize,
+    /// 去掉过期消息后为空被删除的日志段
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+impl fmt::Display for Compaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} expired messages removed, {} log segments rewritten, {} deleted, {} bytes freed",
+            self.expired, self.rewritten, self.removed, self.freed_bytes
+        )
+    }
+}
+
+impl History {
+    /// 以前的聊天记录只有一个日志文件 `<dir>.jsonl`，打开时移动到 `dir` 作为第一个日志段
+    pub async fn open(dir: impl AsRef<Path>, segment_bytes: u64) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await?;
+        let legacy = dir.with_extension(SEGMENT_EXTENSION);
+        if fs::try_exists(&legacy).await? {
+            let path = segment_path(&dir, 1);
+            anyhow::ensure!(
+                !fs::try_exists(&path).await?,
+                "both {} and {} exist, move one of them away",
+                legacy.display(),
+                path.display()
+            );
+            fs::rename(&legacy, &path).await?;
+            info!("Moved {} to {}", legacy.display(), path.display());
+        }
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+                warn!("Remove unfinished compaction file {}", path.display());
+                fs::remove_file(&path).await?;
+            }
+        }
+
+        let segments = segments(&dir).await?;
+        let (records, next_id) = replay_segments(segments.iter().map(|(_, path)| path)).await?;
+        let segment = segments.last().map_or(1, |(n, _)| *n);
+        let log = open_segment(&dir, segment).await?;
+        let segment_len = log.metadata().await?.len();
+        let index = Index::build(records.values());
+        Ok(Self {
+            records,
+            next_id,
+            index,
+            dir,
+            log,
+            segment,
+            segment_len,
+            segment_bytes,
+        })
+    }
+
+    pub async fn post(
+        &mut self,
+        room: &str,
+        sender: &str,
+        content: String,
+        reply_to: Option<u64>,
+        mentions: Vec<String>,
+    ) -> Result<ChatRecord, ChatError> {
+        if let Some(parent) = reply_to {
+            self.get(parent)?;
+        }
+        let entry = LogEntry::Posted {
+            id: self.next_id,
+            at: Utc::now(),
+            room: room.to_string(),
+            sender: sender.to_string(),
+            content,
+            reply_to,
+            mentions,
+        };
+        let id = self.append(entry).await?;
+        Ok(self.records[&id].clone())
+    }
+
+    /// 只有作者本人可以编辑，同时返回编辑后新增的提及
+    pub async fn edit(
+        &mut self,
+        id: u64,
+        editor: &str,
+        content: String,
+        mentions: Vec<String>,
+    ) -> Result<(ChatRecord, Vec<String>), ChatError> {
+        let record = self.get(id)?;
+        if record.sender != editor {
+            return Err(ChatError::NotAuthor(id));
+        }
+        let added = mentions
+            .iter()
+            .filter(|name| !record.mentions.contains(name))
+            .cloned()
+            .collect();
+        let entry = LogEntry::Edited {
+            id,
+            at: Utc::now(),
+            by: editor.to_string(),
+            content,
+            mentions,
+        };
+        self.append(entry).await?;
+        Ok((self.records[&id].clone(), added))
+    }
+
+    /// 作者本人或者 operator 可以删除
+    pub async fn delete(
+        &mut self,
+        id: u64,
+        by: &str,
+        operator: bool,
+    ) -> Result<ChatRecord, ChatError> {
+        let record = self.get(id)?;
+        if record.sender != by && !operator {
+            return Err(ChatError::NotAuthor(id));
+        }
+        let entry = LogEntry::Deleted {
+            id,
+            at: Utc::now(),
+            by: by.to_string(),
+        };
+        self.append(entry).await?;
+        Ok(self.records[&id].clone())
+    }
+
+    /// 房间里最近的 n 条消息，包括已删除的消息，按时间顺序
+    pub fn recent(&self, room: &str, n: usize) -> Vec<&ChatRecord> {
+        let mut records: Vec<&ChatRecord> = self
+            .records
+            .values()
+            .rev()
+            .filter(|record| record.room == room)
+            .take(n)
+            .collect();
+        records.reverse();
+        records
+    }
+
+    /// 所有消息，包括已删除的消息，按 ID 排序
+    pub fn records(&self) -> impl Iterator<Item = &ChatRecord> {
+        self.records.values()
+    }
+
+    /// 匹配的消息，不包括已删除的消息，从新到旧
+    pub fn search<'a>(&'a self, query: &'a SearchQuery) -> impl Iterator<Item = &'a ChatRecord> {
+        self.index
+            .search(&query.tokens)
+            .into_iter()
+            .filter_map(|id| self.records.get(&id))
+            .filter(|record| query.matches(record))
+    }
+
+    /// 查找未被删除的消息
+    pub fn get(&self, id: u64) -> Result<&ChatRecord, ChatError> {
+        match self.records.get(&id) {
+            Some(record) if record.deleted_by.is_some() => Err(ChatError::Deleted(id)),
+            Some(record) => Ok(record),
+            None => Err(ChatError::NotFound(id)),
+        }
+    }
+
+    /// 压缩的第一步：从内存中删除过期的消息，结束当前日志段并记录下一个消息 ID，
+    /// 返回过期消息的 ID 和需要用 `compact` 重写的日志段
+    pub async fn begin_compaction(
+        &mut self,
+        retention: &Retention,
+        now: DateTime<Utc>,
+    ) -> Result<(BTreeSet<u64>, Vec<PathBuf>), ChatError> {
+        let expired = self.expire(retention, now);
+        if expired.is_empty() {
+            return Ok((expired, Vec::new()));
+        }
+        let segments = self.seal().await?;
+        let entry = LogEntry::Compacted {
+            at: now,
+            next_id: self.next_id,
+            expired: expired.len(),
+        };
+        self.append(entry).await?;
+        Ok((expired, segments))
+    }
+
+    /// 按保留策略从内存中删除过期的消息
+    fn expire(&mut self, retention: &Retention, now: DateTime<Utc>) -> BTreeSet<u64> {
+        //每个房间已经保留的消息数和字节数，从新到旧累计
+        let mut kept: HashMap<&str, (usize, u64)> = HashMap::new();
+        let mut expired = BTreeSet::new();
+        for record in self.records.values().rev() {
+            let policy = retention
+                .rooms
+                .get(&record.room)
+                .unwrap_or(&retention.default);
+            let (count, bytes) = kept.entry(record.room.as_str()).or_default();
+            let too_old = policy
+                .max_age_days
+                .is_some_and(|days| now - record.posted_at > chrono::Duration::days(days.into()));
+            let too_many = policy.max_count.is_some_and(|max| *count >= max);
+            let size = record.content.len() as u64;
+            let too_big = policy.max_bytes.is_some_and(|max| *bytes + size > max);
+            if too_old || too_many || too_big {
+                expired.insert(record.id);
+            } else {
+                *count += 1;
+                *bytes += size;
+            }
+        }
+        for id in &expired {
+            self.records.remove(id);
+            self.index.remove(*id);
+        }
+        expired
+    }
+
+    /// 结束当前的日志段，返回所有已经写完、可以重写的日志段
+    async fn seal(&mut self) -> Result<Vec<PathBuf>, ChatError> {
+        if self.segment_len > 0 {
+            self.rotate().await?;
+        }
+        Ok(segments(&self.dir)
+            .await?
+            .into_iter()
+            .filter(|(n, _)| *n < self.segment)
+            .map(|(_, path)| path)
+            .collect())
+    }
+
+    /// 先写日志再更新内存，写失败时内存状态不变
+    async fn append(&mut self, entry: LogEntry) -> Result<u64, ChatError> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.log.write_all(line.as_bytes()).await?;
+        self.log.flush().await?;
+        self.segment_len += line.len() as u64;
+        let id = self.apply(entry);
+        if self.segment_len >= self.segment_bytes {
+            self.rotate().await?;
+        }
+        Ok(id)
+    }
+
+    async fn rotate(&mut self) -> Result<(), ChatError> {
+        self.log.sync_all().await?;
+        self.log = open_segment(&self.dir, self.segment + 1).await?;
+        self.segment += 1;
+        self.segment_len = 0;
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: LogEntry) -> u64 {
+        let id = apply(&mut self.records, entry);
+        self.next_id = self.next_id.max(id + 1);
+        if let Some(record) = self.records.get(&id) {
+            self.index.update(record);
+        }
+        id
+    }
+}
+
+/// 重写日志段，去掉过期消息的所有事件，同时去掉无法解析的行和以前的压缩记录
+pub async fn compact(
+    segments: &[PathBuf],
+    expired: &BTreeSet<u64>,
+) -> Result<Compaction, ChatError> {
+    let mut compaction = Compaction {
+        expired: expired.len(),
+        ..Compaction::default()
+    };
+    if expired.is_empty() {
+        return Ok(compaction);
+    }
+    for path in segments {
+        let content = read_segment(path).await?;
+        let kept: String = content
+            .split_inclusive('\n')
+            .filter(|line| match serde_json::from_str::<LogEntry>(line) {
+                Ok(LogEntry::Compacted { .. }) | Err(_) => false,
+                Ok(entry) => !expired.contains(&entry.id()),
+            })
+            .collect();
+        if kept.len() == content.len() {
+            continue;
+        }
+        compaction.freed_bytes += (content.len() - kept.len()) as u64;
+        if kept.is_empty() {
+            fs::remove_file(path).await?;
+            compaction.removed += 1;
+            continue;
+        }
+        let tmp = path.with_extension(TMP_EXTENSION);
+        let mut file = File::create(&tmp).await?;
+        file.write_all(kept.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, path).await?;
+        compaction.rewritten += 1;
+    }
+    //rename 和删除文件写入目录后才不会在断电时丢失
+    if let Some(dir) = segments.first().and_then(|path| path.parent()) {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(compaction)
+}
+
+/// 只读地重放 `dir` 下的日志，还没有移动到 `dir` 的旧日志文件也会重放
+pub async fn replay(dir: &Path) -> anyhow::Result<BTreeMap<u64, ChatRecord>> {
+    let mut paths = vec![dir.with_extension(SEGMENT_EXTENSION)];
+    paths.extend(segments(dir).await?.into_iter().map(|(_, path)| path));
+    Ok(replay_segments(&paths).await?.0)
+}
+
+/// 同时返回下一个消息 ID
+async fn replay_segments(
+    paths: impl IntoIterator<Item = impl AsRef<Path>>,
+) -> anyhow::Result<(BTreeMap<u64, ChatRecord>, u64)> {
+    let mut records = BTreeMap::new();
+    let mut next_id = 1;
+    for path in paths {
+        let path = path.as_ref();
+        let content = read_segment(path).await?;
+        for (n, line) in content.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(entry) => {
+                    next_id = next_id.max(apply(&mut records, entry) + 1);
+                }
+                Err(e) => warn!("Skip broken entry at {}:{}: {e}", path.display(), n + 1),
+            }
+        }
+    }
+    Ok((records, next_id))
+}
+
+/// 日志段不存在时返回空字符串，压缩时可能刚好删除了这个日志段
+async fn read_segment(path: &Path) -> std::io::Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 按编号排序的日志段，目录不存在时为空
+async fn segments(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != SEGMENT_EXTENSION) {
+            continue;
+        }
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{segment:08}.{SEGMENT_EXTENSION}"))
+}
+
+async fn open_segment(dir: &Path, segment: u64) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))
+        .await
+}
+
+impl LogEntry {
+    /// 压缩记录没有对应的消息，返回它之前最后一个消息 ID
+    fn id(&self) -> u64 {
+        match self {
+            Self::Posted { id, .. } | Self::Edited { id, .. } | Self::Deleted { id, .. } => *id,
+            Self::Compacted { next_id, .. } => next_id.saturating_sub(1),
+        }
+    }
+}
+
+fn apply(records: &mut BTreeMap<u64, ChatRecord>, entry: LogEntry) -> u64 {
+    match entry {
+        LogEntry::Posted {
+            id,
+            at,
+            room,
+            sender,
+            content,
+            reply_to,
+            mentions,
+        } => {
+            let record = ChatRecord {
+                id,
+                room,
+                posted_at: at,
+                sender,
+                content,
+                reply_to,
+                mentions,
+                edited_at: None,
+                deleted_by: None,
+            };
+            records.insert(id, record);
+            id
+        }
+        LogEntry::Edited {
+            id,
+            at,
+            content,
+            mentions,
+            ..
+        } => {
+            if let Some(record) = records.get_mut(&id) {
+                record.content = content;
+                record.mentions = mentions;
+                record.edited_at = Some(at);
+            }
+            id
+        }
+        LogEntry::Deleted { id, by, .. } => {
+            if let Some(record) = records.get_mut(&id) {
+                record.content.clear();
+                record.deleted_by = Some(by);
+            }
+            id
+        }
+        entry @ LogEntry::Compacted { .. } => entry.id(),
+    }
+}
+
+fn default_room() -> String {
+    LEGACY_ROOM.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetentionPolicy;
+
+    /// 足够小，每几条消息就换一个日志段
+    const SEGMENT_BYTES: u64 = 256;
+
+    async fn open(dir: &Path) -> History {
+        History::open(dir.join("messages"), SEGMENT_BYTES)
+            .await
+            .unwrap()
+    }
+
+    async fn post(history: &mut History, room: &str, content: &str) -> u64 {
+        history
+            .post(room, "alice", content.to_string(), None, Vec::new())
+            .await
+            .unwrap()
+            .id
+    }
+
+    async fn compact_now(history: &mut History, retention: &Retention, now: DateTime<Utc>) {
+        let (expired, segments) = history.begin_compaction(retention, now).await.unwrap();
+        compact(&segments, &expired).await.unwrap();
+    }
+
+    fn ids(history: &History) -> Vec<u64> {
+        history.records().map(|record| record.id).collect()
+    }
+
+    fn retention(rooms: &[(&str, RetentionPolicy)]) -> Retention {
+        Retention {
+            rooms: rooms
+                .iter()
+                .map(|(room, policy)| (room.to_string(), policy.clone()))
+                .collect(),
+            ..Retention::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn each_room_uses_its_own_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(dir.path()).await;
+        for n in 0..4 {
+            post(&mut history, "#random", &format!("random {n}")).await;
+            post(&mut history, "#general", &format!("general {n}")).await;
+        }
+        let retention = retention(&[(
+            "#random",
+            RetentionPolicy {
+                max_count: Some(2),
+                ..RetentionPolicy::default()
+            },
+        )]);
+        compact_now(&mut history, &retention, Utc::now()).await;
+        assert_eq!(ids(&history), [2, 4, 5, 6, 7, 8]);
+        drop(history);
+
+        let history = open(dir.path()).await;
+        assert_eq!(ids(&history), [2, 4, 5, 6, 7, 8]);
+    }
+
+    #[tokio::test]
+    async fn old_messages_expire_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(dir.path()).await;
+        post(&mut history, "#general", "hello").await;
+        let retention = retention(&[(
+            "#general",
+            RetentionPolicy {
+                max_age_days: Some(1),
+                ..RetentionPolicy::default()
+            },
+        )]);
+        compact_now(&mut history, &retention, Utc::now()).await;
+        assert_eq!(ids(&history), [1]);
+        let later = Utc::now() + chrono::Duration::days(2);
+        compact_now(&mut history, &retention, later).await;
+        assert!(ids(&history).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ids_are_not_reused_after_the_newest_messages_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(dir.path()).await;
+        for n in 0..5 {
+            post(&mut history, "#general", &format!("message {n}")).await;
+        }
+        let retention = retention(&[(
+            "#general",
+            RetentionPolicy {
+                max_age_days: Some(1),
+                ..RetentionPolicy::default()
+            },
+        )]);
+        let later = Utc::now() + chrono::Duration::days(2);
+        compact_now(&mut history, &retention, later).await;
+        assert!(ids(&history).is_empty());
+        drop(history);
+
+        //只剩下 Compacted 记录，重放之后仍然从 6 开始
+        let mut history = open(dir.path()).await;
+        assert!(ids(&history).is_empty());
+        assert_eq!(post(&mut history, "#general", "after").await, 6);
+    }
+
+    #[tokio::test]
+    async fn expired_messages_come_back_until_the_rewrite_finishes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(dir.path()).await;
+        for n in 0..5 {
+            post(&mut history, "#general", &format!("message {n}")).await;
+        }
+        let retention = retention(&[(
+            "#general",
+            RetentionPolicy {
+                max_count: Some(2),
+                ..RetentionPolicy::default()
+            },
+        )]);
+        //在重写日志段之前崩溃
+        history
+            .begin_compaction(&retention, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(ids(&history), [4, 5]);
+        drop(history);
+
+        let mut history = open(dir.path()).await;
+        assert_eq!(ids(&history), [1, 2, 3, 4, 5]);
+        compact_now(&mut history, &retention, Utc::now()).await;
+        drop(history);
+        let history = open(dir.path()).await;
+        assert_eq!(ids(&history), [4, 5]);
+    }
+
+    #[tokio::test]
+    async fn open_removes_unfinished_rewrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(dir.path()).await;
+        post(&mut history, "#general", "hello").await;
+        drop(history);
+
+        let segments_dir = dir.path().join("messages");
+        let tmp = segment_path(&segments_dir, 1).with_extension(TMP_EXTENSION);
+        fs::write(&tmp, "half written").await.unwrap();
+        let history = open(dir.path()).await;
+        assert!(!fs::try_exists(&tmp).await.unwrap());
+        assert_eq!(ids(&history), [1]);
+    }
+}
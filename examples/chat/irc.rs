@@ -0,0 +1,709 @@
+/*
+IRC 前端
+    - 支持 PASS、NICK、USER、JOIN、PART、PRIVMSG、QUIT、PING/PONG、NAMES、TOPIC
+    - MODE 支持房间设置：+i 仅限邀请、+k 密码、+l 人数上限、+m 公告模式、+o 管理员，INVITE 邀请用户
+    - 为了让 weechat/irssi 这类客户端正常工作，还简单应答 CAP、WHO、LIST
+    - IRC 的频道就是 state 中的房间，IRC 用户和行协议用户互相可见
+    - 注册过的昵称需要先发送 PASS
+*/
+
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    error::ChatError,
+    message::Message,
+    rooms::{RoomChange, RoomSettings},
+    state::{normalize_room, validate_username, Delivery, PeerId, State},
+    transport::Connection,
+};
+
+const SERVER_NAME: &str = "ecosystem.chat";
+const NAMES_PER_LINE: usize = 30;
+
+#[derive(Debug, PartialEq)]
+struct IrcLine {
+    command: String,
+    params: Vec<String>,
+}
+
+pub async fn handle_request(
+    state: Arc<State>,
+    peer_id: PeerId,
+    stream: impl Connection,
+) -> anyhow::Result<()> {
+    let mut framed = Framed::new(stream, LinesCodec::new());
+    let Some(nick) = register(&state, peer_id, &mut framed).await? else {
+        return Ok(());
+    };
+    let guard = state.guard(peer_id);
+    info!("{nick} connected via IRC as {peer_id}");
+
+    let mut rx = state.add(peer_id, nick.clone());
+    let (mut sink, mut stream) = framed.split();
+    let writer_state = state.clone();
+    let writer_nick = nick.clone();
+    //写失败说明 client 已经断开
+    tokio::spawn(async move {
+        'messages: while let Some(message) = rx.recv().await {
+            for line in render(&writer_state, &writer_nick, &message) {
+                if let Err(e) = sink.send(line + "\r").await {
+                    warn!("Fail to send message to {peer_id}:{e}");
+                    break 'messages;
+                }
+            }
+        }
+    });
+
+    let session = Session {
+        state: &state,
+        peer_id,
+        nick,
+    };
+    session.welcome().await?;
+
+    while let Some(line) = stream.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to read line from {}: {}", peer_id, e);
+                break;
+            }
+        };
+        let Some(line) = parse(&line) else {
+            continue;
+        };
+        if !session.handle(line).await {
+            break;
+        }
+    }
+    guard.remove().await;
+    Ok(())
+}
+
+/// 注册阶段：收集 NICK、USER 和可选的 PASS，返回 None 表示 client 放弃或者密码错误
+async fn register(
+    state: &State,
+    peer_id: PeerId,
+    framed: &mut Framed<impl Connection, LinesCodec>,
+) -> anyhow::Result<Option<String>> {
+    let mut password = None;
+    let mut nick: Option<String> = None;
+    let mut user = false;
+    while let Some(line) = framed.next().await {
+        let Some(IrcLine {
+            command,
+            mut params,
+        }) = parse(&line?)
+        else {
+            continue;
+        };
+        let target = nick.clone().unwrap_or_else(|| "*".to_string());
+        match command.as_str() {
+            "CAP" => {
+                if params.first().map(String::as_str) == Some("LS") {
+                    send(framed, format!(":{SERVER_NAME} CAP * LS :")).await?;
+                }
+            }
+            "PASS" if !params.is_empty() => password = Some(params.swap_remove(0)),
+            "NICK" if !params.is_empty() => {
+                let candidate = params.swap_remove(0);
+                match validate_username(&candidate) {
+                    Ok(()) => nick = Some(candidate),
+                    Err(e) => {
+                        let line = numeric(&target, "432", &[&candidate], &e.to_string());
+                        send(framed, line).await?;
+                    }
+                }
+            }
+            "USER" if params.len() >= 4 => user = true,
+            "PASS" | "NICK" | "USER" => {
+                let line = numeric(&target, "461", &[&command], "Not enough parameters");
+                send(framed, line).await?;
+            }
+            "PING" => {
+                let token = params.first().map(String::as_str).unwrap_or(SERVER_NAME);
+                send(
+                    framed,
+                    format!(":{SERVER_NAME} PONG {SERVER_NAME} :{token}"),
+                )
+                .await?;
+            }
+            "QUIT" => return Ok(None),
+            _ => {
+                let line = numeric(&target, "451", &[], "You have not registered");
+                send(framed, line).await?;
+            }
+        }
+
+        let Some(candidate) = nick.as_deref() else {
+            continue;
+        };
+        if !user {
+            continue;
+        }
+        if state.accounts.lock().await.is_registered(candidate) {
+            let verified = match &password {
+                Some(password) => state.login(candidate, password).await?,
+                None => false,
+            };
+            if !verified {
+                warn!("Wrong password for {candidate} from {peer_id}");
+                let line = numeric(candidate, "464", &[], "Password incorrect");
+                send(framed, line).await?;
+                send(
+                    framed,
+                    "ERROR :Closing link (password incorrect)".to_string(),
+                )
+                .await?;
+                return Ok(None);
+            }
+        }
+        if state.claim(candidate, peer_id) {
+            return Ok(nick);
+        }
+        let line = numeric("*", "433", &[candidate], "Nickname is already in use");
+        send(framed, line).await?;
+        nick = None;
+    }
+    Ok(None)
+}
+
+struct Session<'a> {
+    state: &'a State,
+    peer_id: PeerId,
+    nick: String,
+}
+
+impl Session<'_> {
+    async fn reply(&self, line: String) {
+        self.state
+            .send(self.peer_id, Arc::new(Message::Raw(line)))
+            .await;
+    }
+
+    async fn numeric(&self, code: &str, params: &[&str], text: &str) {
+        self.reply(numeric(&self.nick, code, params, text)).await;
+    }
+
+    async fn welcome(&self) -> anyhow::Result<()> {
+        let nick = self.nick.as_str();
+        self.numeric(
+            "001",
+            &[],
+            &format!("Welcome to the ecosystem chat, {nick}"),
+        )
+        .await;
+        self.numeric("002", &[], &format!("Your host is {SERVER_NAME}"))
+            .await;
+        self.numeric("003", &[], "This server was created just now")
+            .await;
+        self.reply(format!(
+            ":{SERVER_NAME} 004 {nick} {SERVER_NAME} ecosystem-{} i t",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .await;
+        let limits = &config::get().limits;
+        let isupport = format!(
+            "CHANTYPES=# NICKLEN={} CHANNELLEN={}",
+            limits.max_username_len,
+            limits.max_room_name_len + 1
+        );
+        self.numeric("005", &[&isupport], "are supported by this server")
+            .await;
+        self.numeric("422", &[], "MOTD File is missing").await;
+
+        let (letters, unread) = self.state.take_offline(nick).await?;
+        if !letters.is_empty() {
+            let notice = format!("you have {} new messages", letters.len());
+            self.state
+                .send(self.peer_id, Arc::new(Message::notice(notice)))
+                .await;
+        }
+        for letter in letters {
+            self.state
+                .send(self.peer_id, Arc::new(Message::letter(letter)))
+                .await;
+        }
+        if unread > 0 {
+            let notice = format!("you have {unread} unread mentions");
+            self.state
+                .send(self.peer_id, Arc::new(Message::notice(notice)))
+                .await;
+        }
+        Ok(())
+    }
+
+    /// 处理一条命令，返回 false 表示 client 退出
+    async fn handle(&self, line: IrcLine) -> bool {
+        let IrcLine { command, params } = line;
+        let nick = self.nick.as_str();
+        let param = |i: usize| params.get(i).map(String::as_str);
+        match (command.as_str(), param(0)) {
+            ("PING", token) => {
+                let token = token.unwrap_or(SERVER_NAME);
+                self.reply(format!(":{SERVER_NAME} PONG {SERVER_NAME} :{token}"))
+                    .await;
+            }
+            ("PONG", _) => {}
+            ("QUIT", _) => {
+                self.reply("ERROR :Closing link".to_string()).await;
+                return false;
+            }
+            ("CAP", Some("LS")) => {
+                self.reply(format!(":{SERVER_NAME} CAP {nick} LS :")).await;
+            }
+            ("CAP", _) => {}
+            ("NICK", Some(new)) if new == nick => {}
+            ("NICK", Some(_)) => {
+                self.reply(format!(
+                    ":{SERVER_NAME} NOTICE {nick} :changing nick is not supported"
+                ))
+                .await;
+            }
+            ("USER" | "PASS", _) => {
+                self.numeric("462", &[], "You may not reregister").await;
+            }
+            ("JOIN", Some(rooms)) => {
+                let mut passwords = param(1).unwrap_or_default().split(',');
+                for room in rooms.split(',') {
+                    self.join(room, passwords.next()).await;
+                }
+            }
+            ("PART", Some(rooms)) => {
+                for room in rooms.split(',') {
+                    if let Err(e) = self.state.part(nick, room).await {
+                        self.error(&command, e).await;
+                    }
+                }
+            }
+            ("PRIVMSG", None) => {
+                self.numeric("411", &[], "No recipient given (PRIVMSG)")
+                    .await;
+            }
+            ("PRIVMSG", Some(_)) if params.len() < 2 => {
+                self.numeric("412", &[], "No text to send").await;
+            }
+            ("PRIVMSG", Some(target)) => {
+                let content = params[1].clone();
+                self.privmsg(target, content).await;
+            }
+            ("NOTICE", _) => {}
+            ("NAMES", Some(rooms)) => {
+                for room in rooms.split(',') {
+                    self.names(room).await;
+                }
+            }
+            ("NAMES", None) => self.numeric("366", &["*"], "End of /NAMES list").await,
+            ("TOPIC", Some(room)) => match param(1) {
+                Some(topic) => {
+                    let topic = topic.to_string();
+                    let result = self.state.set_topic(nick, room, topic, false).await;
+                    if let Err(e) = result {
+                        self.error(&command, e).await;
+                    }
+                }
+                None => self.topic(room).await,
+            },
+            ("MODE", Some(room)) if room.starts_with('#') => match param(1) {
+                None => match self.state.room(room) {
+                    Ok(info) => {
+                        let modes = modes(&info.settings).join(" ");
+                        self.reply(format!(":{SERVER_NAME} 324 {nick} {room} {modes}"))
+                            .await;
+                    }
+                    Err(e) => self.error(&command, e).await,
+                },
+                //客户端加入频道后会查询封禁列表，这里没有封禁
+                Some("b" | "+b") if params.len() == 2 => {
+                    self.numeric("368", &[room], "End of channel ban list")
+                        .await;
+                }
+                Some(changes) => self.mode(room, changes, &params[2..]).await,
+            },
+            ("MODE", Some(_)) => self.numeric("221", &["+"], "").await,
+            ("WHO", Some(room)) => {
+                for name in self.state.names(room).unwrap_or_default() {
+                    let (user, flags) = match name.strip_prefix('@') {
+                        Some(user) => (user, "H@"),
+                        None => (name.as_str(), "H"),
+                    };
+                    let params = [room, user, SERVER_NAME, SERVER_NAME, user, flags];
+                    self.numeric("352", &params, &format!("0 {user}")).await;
+                }
+                self.numeric("315", &[room], "End of /WHO list").await;
+            }
+            ("LIST", _) => {
+                self.numeric("321", &["Channel"], "Users  Name").await;
+                for room in self.state.rooms() {
+                    let members = room.members.to_string();
+                    let topic = format!(
+                        "[{}] {}",
+                        modes(&room.settings)[0],
+                        room.settings.topic.unwrap_or_default()
+                    );
+                    self.numeric("322", &[&room.name, &members], &topic).await;
+                }
+                self.numeric("323", &[], "End of /LIST").await;
+            }
+            ("INVITE", Some(target)) if params.len() >= 2 => {
+                let room = params[1].as_str();
+                match self.state.invite(nick, room, target, false).await {
+                    Ok(()) => self.reply(numeric(nick, "341", &[target], room)).await,
+                    Err(e) => self.error(&command, e).await,
+                }
+            }
+            ("JOIN" | "PART" | "NICK" | "TOPIC" | "MODE" | "WHO" | "INVITE", _) => {
+                self.numeric("461", &[&command], "Not enough parameters")
+                    .await;
+            }
+            _ => self.numeric("421", &[&command], "Unknown command").await,
+        }
+        true
+    }
+
+    async fn join(&self, room: &str, password: Option<&str>) {
+        if !room.starts_with('#') {
+            self.numeric("403", &[room], "No such channel").await;
+            return;
+        }
+        let room = match normalize_room(room) {
+            Ok(room) => room,
+            Err(e) => return self.error("JOIN", e).await,
+        };
+        //JOIN 的回显来自 state 的广播，之后再回复话题和用户列表
+        let password = password.filter(|password| !password.is_empty());
+        match self
+            .state
+            .join(self.peer_id, &self.nick, &room, password)
+            .await
+        {
+            Ok(true) => {
+                self.topic(&room).await;
+                self.names(&room).await;
+            }
+            Ok(false) => {}
+            Err(e) => self.error("JOIN", e).await,
+        }
+    }
+
+    /// `MODE #room +ik-l key`，每个修改单独生效
+    async fn mode(&self, room: &str, changes: &str, args: &[String]) {
+        let mut args = args.iter();
+        let mut on = true;
+        for mode in changes.chars() {
+            let change = match mode {
+                '+' | '-' => {
+                    on = mode == '+';
+                    continue;
+                }
+                //话题总是只有管理员可以修改
+                't' => continue,
+                'i' => RoomChange::InviteOnly(on),
+                'm' => RoomChange::Announce(on),
+                'k' if on => match args.next() {
+                    Some(password) => RoomChange::Password(Some(password.clone())),
+                    None => {
+                        return self
+                            .numeric("461", &["MODE"], "Not enough parameters")
+                            .await
+                    }
+                },
+                'k' => {
+                    args.next();
+                    RoomChange::Password(None)
+                }
+                'l' if on => match args.next().and_then(|max| max.parse().ok()) {
+                    Some(max) if max > 0 => RoomChange::MaxMembers(Some(max)),
+                    _ => {
+                        return self
+                            .numeric("461", &["MODE"], "Not enough parameters")
+                            .await
+                    }
+                },
+                'l' => RoomChange::MaxMembers(None),
+                'o' => match args.next() {
+                    Some(user) if on => RoomChange::Op(user.clone()),
+                    Some(user) => RoomChange::Deop(user.clone()),
+                    None => {
+                        return self
+                            .numeric("461", &["MODE"], "Not enough parameters")
+                            .await
+                    }
+                },
+                mode => {
+                    let mode = mode.to_string();
+                    self.numeric("472", &[&mode], "is unknown mode char to me")
+                        .await;
+                    continue;
+                }
+            };
+            if let Err(e) = self.state.configure(&self.nick, room, change, false).await {
+                return self.error("MODE", e).await;
+            }
+        }
+    }
+
+    async fn topic(&self, room: &str) {
+        match self.state.topic(room) {
+            Ok(Some(topic)) => self.numeric("332", &[room], &topic).await,
+            Ok(None) => self.numeric("331", &[room], "No topic is set").await,
+            Err(e) => self.error("TOPIC", e).await,
+        }
+    }
+
+    async fn names(&self, room: &str) {
+        match self.state.names(room) {
+            Ok(users) => {
+                let message = Message::Names {
+                    room: room.to_string(),
+                    users,
+                };
+                self.state.send(self.peer_id, Arc::new(message)).await;
+            }
+            Err(_) => self.numeric("366", &[room], "End of /NAMES list").await,
+        }
+    }
+
+    async fn privmsg(&self, target: &str, content: String) {
+        let result = if target.starts_with('#') {
+            self.state
+                .post(&self.nick, target, content, None)
+                .await
+                .map(|_| ())
+        } else {
+            match self.state.direct(&self.nick, target, content).await {
+                Ok(Delivery::Online) => Ok(()),
+                Ok(Delivery::Mailbox) => {
+                    let notice = format!(
+                        "{target} is offline, the message will be delivered at their next login"
+                    );
+                    self.state
+                        .send(self.peer_id, Arc::new(Message::notice(notice)))
+                        .await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = result {
+            self.error("PRIVMSG", e).await;
+        }
+    }
+
+    /// 把 ChatError 转换成对应的数字回复，没有对应关系的用 NOTICE
+    async fn error(&self, command: &str, e: ChatError) {
+        match e {
+            ChatError::NoSuchRoom(room) | ChatError::InvalidRoom(room) => {
+                self.numeric("403", &[&room], "No such channel").await
+            }
+            ChatError::NotInRoom(room) if command == "PRIVMSG" => {
+                self.numeric("404", &[&room], "Cannot send to channel")
+                    .await
+            }
+            ChatError::NotInRoom(room) => {
+                self.numeric("442", &[&room], "You're not on that channel")
+                    .await
+            }
+            ChatError::ReadOnlyRoom(room) => {
+                self.numeric("404", &[&room], "Cannot send to channel")
+                    .await
+            }
+            ChatError::Blocked(target) => {
+                self.numeric(
+                    "404",
+                    &[&target],
+                    "Cannot send to channel (blocked by content filter)",
+                )
+                .await
+            }
+            ChatError::EmptyMessage => self.numeric("412", &[], "No text to send").await,
+            ChatError::InviteOnly(room) => {
+                self.numeric("473", &[&room], "Cannot join channel (+i)")
+                    .await
+            }
+            ChatError::BadRoomPassword(room) => {
+                self.numeric("475", &[&room], "Cannot join channel (+k)")
+                    .await
+            }
+            ChatError::RoomFull(room) => {
+                self.numeric("471", &[&room], "Cannot join channel (+l)")
+                    .await
+            }
+            ChatError::NotRoomOperator(room) | ChatError::NotRoomOwner(room) => {
+                self.numeric("482", &[&room], "You're not channel operator")
+                    .await
+            }
+            ChatError::UserNotFound(user) => {
+                self.numeric("401", &[&user], "No such nick/channel").await
+            }
+            e => {
+                self.state
+                    .send(self.peer_id, Arc::new(Message::notice(e.to_string())))
+                    .await
+            }
+        }
+    }
+}
+
+/// 把事件渲染成 IRC 协议行，IRC 客户端会在本地显示自己发送的消息，所以不回显
+fn render(state: &State, nick: &str, message: &Message) -> Vec<String> {
+    let line = match message {
+        Message::Joined { room, username } => format!(":{} JOIN {}", prefix(username), room),
+        Message::Parted { room, username } => format!(":{} PART {}", prefix(username), room),
+        Message::Quit(username) => format!(":{} QUIT :Quit", prefix(username)),
+        Message::Chat(record) if record.sender == nick => return Vec::new(),
+        Message::Chat(record) => {
+            let mut content = record.content.clone();
+            if let Some(parent) = record.reply_to {
+                content = format!("(re #{parent}) {content}");
+            }
+            format!(
+                ":{} PRIVMSG {} :{}",
+                prefix(&record.sender),
+                record.room,
+                content
+            )
+        }
+        Message::Edited(record) => format!(
+            ":{SERVER_NAME} NOTICE {} :[#{} edited] {}: {}",
+            record.room, record.id, record.sender, record.content
+        ),
+        //已经在房间里的用户会在频道中看到这条消息，客户端自己会高亮
+        Message::Mention(record) if state.is_member(nick, &record.room) => return Vec::new(),
+        Message::Mention(record) => format!(
+            ":{SERVER_NAME} NOTICE {nick} :[mention] [{}] {}: {}",
+            record.room, record.sender, record.content
+        ),
+        Message::Deleted { id, room, by } => {
+            format!(":{SERVER_NAME} NOTICE {room} :[#{id} deleted by {by}]")
+        }
+        Message::Direct {
+            from,
+            content,
+            sent_at: None,
+        } => format!(":{} PRIVMSG {} :{}", prefix(from), nick, content),
+        Message::Direct {
+            from,
+            content,
+            sent_at: Some(at),
+        } => format!(
+            ":{} PRIVMSG {} :[{}] {}",
+            prefix(from),
+            nick,
+            at.format("%Y-%m-%d %H:%M UTC"),
+            content
+        ),
+        Message::Topic { room, by, topic } => {
+            format!(":{} TOPIC {} :{}", prefix(by), room, topic)
+        }
+        Message::Names { room, users } => {
+            let mut lines: Vec<String> = users
+                .chunks(NAMES_PER_LINE)
+                .map(|users| numeric(nick, "353", &["=", room], &users.join(" ")))
+                .collect();
+            lines.push(numeric(nick, "366", &[room], "End of /NAMES list"));
+            return lines;
+        }
+        Message::RoomChanged { room, by, change } => {
+            format!(":{} MODE {} {}", prefix(by), room, mode_change(change))
+        }
+        Message::Invited { room, by } => format!(":{} INVITE {} {}", prefix(by), nick, room),
+        Message::Notice(content) => format!(":{SERVER_NAME} NOTICE {nick} :{content}"),
+        Message::Raw(line) => line.clone(),
+    };
+    vec![line]
+}
+
+/// `:server <code> <nick> [params] :<text>`
+fn numeric(nick: &str, code: &str, params: &[&str], text: &str) -> String {
+    let mut line = format!(":{SERVER_NAME} {code} {nick}");
+    for param in params {
+        line.push(' ');
+        line.push_str(param);
+    }
+    line.push_str(" :");
+    line.push_str(text);
+    line
+}
+
+/// 频道模式和参数，密码不会发给客户端
+fn modes(settings: &RoomSettings) -> Vec<String> {
+    let mut flags = "+t".to_string();
+    let mut params = Vec::new();
+    if settings.invite_only {
+        flags.push('i');
+    }
+    if settings.password.is_some() {
+        flags.push('k');
+        params.push("*".to_string());
+    }
+    if let Some(max) = settings.max_members {
+        flags.push('l');
+        params.push(max.to_string());
+    }
+    if settings.announce {
+        flags.push('m');
+    }
+    params.insert(0, flags);
+    params
+}
+
+fn mode_change(change: &RoomChange) -> String {
+    let switch = |on: &bool| if *on { '+' } else { '-' };
+    match change {
+        RoomChange::Op(user) => format!("+o {user}"),
+        RoomChange::Deop(user) => format!("-o {user}"),
+        RoomChange::InviteOnly(on) => format!("{}i", switch(on)),
+        RoomChange::Password(Some(_)) => "+k *".to_string(),
+        RoomChange::Password(None) => "-k *".to_string(),
+        RoomChange::MaxMembers(Some(max)) => format!("+l {max}"),
+        RoomChange::MaxMembers(None) => "-l".to_string(),
+        RoomChange::Announce(on) => format!("{}m", switch(on)),
+    }
+}
+
+fn prefix(nick: &str) -> String {
+    format!("{nick}!{nick}@{SERVER_NAME}")
+}
+
+async fn send(
+    framed: &mut Framed<impl Connection, LinesCodec>,
+    line: String,
+) -> anyhow::Result<()> {
+    framed.send(line + "\r").await?;
+    Ok(())
+}
+
+/// `[:prefix] COMMAND param... [:trailing]`，client 发来的 prefix 直接忽略
+fn parse(line: &str) -> Option<IrcLine> {
+    let mut rest = line.trim();
+    if let Some(prefixed) = rest.strip_prefix(':') {
+        rest = prefixed.split_once(' ')?.1.trim_start();
+    }
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    if command.is_empty() {
+        return None;
+    }
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing.to_string());
+            break;
+        }
+        let (param, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+        params.push(param.to_string());
+        rest = remaining;
+    }
+    Some(IrcLine {
+        command: command.to_ascii_uppercase(),
+        params,
+    })
+}
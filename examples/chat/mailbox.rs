@@ -0,0 +1,86 @@
+/*
+离线信箱
+    - 发给不在线的注册用户的私信保存在信箱中，下次登录时投递
+    - 每个信箱有容量上限，超过时拒绝新的私信
+    - 超过保存期限的私信在读取或写入信箱时清理
+*/
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::ChatError,
+    storage::{load_json, save_json},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Letter {
+    pub from: String,
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct Mailbox {
+    path: PathBuf,
+    quota: usize,
+    ttl: Duration,
+    letters: BTreeMap<String, Vec<Letter>>,
+}
+
+impl Mailbox {
+    pub async fn open(path: impl AsRef<Path>, quota: usize, ttl: Duration) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut mailbox = Self {
+            letters: load_json(&path).await?,
+            path,
+            quota,
+            ttl,
+        };
+        if mailbox.expire() {
+            mailbox.save().await?;
+        }
+        Ok(mailbox)
+    }
+
+    pub async fn deliver(&mut self, to: &str, letter: Letter) -> Result<(), ChatError> {
+        self.expire();
+        let letters = self.letters.entry(to.to_string()).or_default();
+        if letters.len() >= self.quota {
+            return Err(ChatError::MailboxFull(to.to_string()));
+        }
+        letters.push(letter);
+        self.save().await
+    }
+
+    /// 取出并清空信箱
+    pub async fn take(&mut self, username: &str) -> Result<Vec<Letter>, ChatError> {
+        let expired = self.expire();
+        let letters = self.letters.remove(username).unwrap_or_default();
+        if expired || !letters.is_empty() {
+            self.save().await?;
+        }
+        Ok(letters)
+    }
+
+    /// 清理过期的私信，返回是否有改动
+    fn expire(&mut self) -> bool {
+        let now = Utc::now();
+        let mut changed = false;
+        self.letters.retain(|_, letters| {
+            let before = letters.len();
+            letters.retain(|letter| now - letter.sent_at < self.ttl);
+            changed |= letters.len() != before;
+            !letters.is_empty()
+        });
+        changed
+    }
+
+    async fn save(&self) -> Result<(), ChatError> {
+        save_json(&self.path, &self.letters).await
+    }
+}
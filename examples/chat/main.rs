@@ -0,0 +1,250 @@
+/*
+写一个简单的Tcp Chat Server
+    - client 连接：登录
+        - 用户名唯一，已注册的用户名需要输入密码
+    - client 登录成功：添加全局状态
+        - 创建 peer
+        - 自动加入默认房间 #general，通知房间里的小伙伴
+    - client 断连：从全局状态删除
+        - 通知所在房间的小伙伴
+    - client 发消息
+        - 写入聊天记录，分配消息 ID
+        - 广播到当前房间
+        - 单独通知消息中 @ 到的在线用户和注册用户
+    - client 登录成功后投递离线信箱中的私信
+    - client 发命令
+        - /join <room> [password]、/part [room]：加入、离开房间，/join 同时切换当前房间
+        - /list：回复所有房间及其设置，/topic [text]：查看或设置当前房间的话题
+        - /who：回复当前房间的用户列表和设置
+        - /op <user>、/deop <user>：房主任命、撤销房间管理员
+        - /invite <user>、/set <name> <value>：房间管理员邀请用户、修改房间设置
+        - /reply <id> <text>：回复某条消息
+        - /edit <id> <text>、/delete <id>：编辑、删除消息，通知房间里的小伙伴
+        - /history [n]：回复当前房间最近的 n 条消息
+        - /oper <password>：成为 operator，可以删除任何人的消息
+        - /register <password>：注册当前用户名
+        - /mentions：回复并清空未读的 @ 提及
+        - /msg <username> <text>：私信，注册用户不在线时存入离线信箱
+        - /search <terms> [in:room] [from:user] [page:n]：全文搜索聊天记录，支持中文
+        - /export <jsonl|md|html> [room] [from..to]：operator 导出聊天记录到数据目录
+        - /compact：operator 立即按保留策略压缩聊天记录
+    - 另外在 6667 端口提供 IRC 前端，见 irc.rs，IRC 用户和行协议用户共享房间
+    - 行协议同时监听数据目录下的 Unix domain socket，`chat serve --console` 让服务器的 stdin/stdout 作为 operator 加入聊天
+    - 在数据目录的 webhooks.json 中配置外发 webhook，把消息、提及、加入和离开房间的事件 POST 到其他服务
+    - 监听地址、各种限制、默认房间、operator 密码、数据目录和日志级别都可以配置，见 common/chat_config.rs
+    - `delivery.fanout`（或者 `--fanout <queue|broadcast>`）选择消息分发的方式，见 fanout.rs
+    - 消息在广播之前经过内容过滤：去掉终端控制字符、屏蔽词和正则、遮住密钥，每个房间可以有自己的策略，见 filter.rs
+    - 每个房间可以配置聊天记录的保留策略（最长时间、最多条数、最大字节数），后台定期压缩日志，见 history.rs
+    - `chat export` 子命令直接读取磁盘上的日志导出聊天记录，不需要启动服务
+*/
+
+#[path = "../common/chat_config.rs"]
+mod config;
+
+mod accounts;
+mod command;
+mod error;
+mod export;
+mod fanout;
+mod filter;
+mod history;
+mod irc;
+mod mailbox;
+mod mention;
+mod message;
+mod native;
+mod rooms;
+mod search;
+mod state;
+mod storage;
+mod transport;
+mod webhook;
+
+use clap::{Args, Parser, Subcommand};
+use config::{Config, ConfigArgs};
+use export::{Format, Selection};
+use state::{PeerId, State};
+use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{fs, net::TcpListener};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    fmt::{writer::BoxMakeWriter, Layer},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer as _,
+};
+use transport::Listener;
+
+/// A TCP chat server with an IRC front end
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Run the chat server (the default)
+    Serve(ServeArgs),
+    /// Export the chat history from the on-disk log
+    Export {
+        /// jsonl, md or html
+        #[arg(short, long, default_value = "md")]
+        format: Format,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// A room name and/or ranges like `#10..#20` or `2024-05-01..2024-05-02T12:00`
+        selection: Vec<String>,
+    },
+}
+
+#[derive(Debug, Default, Args)]
+struct ServeArgs {
+    /// Join the chat as an operator from this terminal, logs go to stderr
+    #[arg(long)]
+    console: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config)?;
+    if cli.config.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let config = config.install();
+    match cli
+        .command
+        .unwrap_or_else(|| Cmd::Serve(ServeArgs::default()))
+    {
+        Cmd::Serve(args) => run_server(config, args).await,
+        Cmd::Export {
+            format,
+            output,
+            selection,
+        } => run_export(config, format, output, selection).await,
+    }
+}
+
+async fn run_server(config: &Config, args: ServeArgs) -> anyhow::Result<()> {
+    //控制台模式下 stdout 用来聊天，日志写到 stderr
+    let writer = if args.console {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let layer = Layer::new()
+        .with_writer(writer)
+        .with_filter(config.level()?);
+    tracing_subscriber::registry().with(layer).init();
+
+    let addr = config.listen.native;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start chat server on {addr}");
+    let irc_addr = config.listen.irc;
+    let irc_listener = TcpListener::bind(irc_addr).await?;
+    info!("Start IRC server on {irc_addr}");
+    let unix_listener = match config.unix_socket() {
+        Some(path) => {
+            let listener = transport::bind_unix(path).await?;
+            info!("Start chat server on {}", path.display());
+            Some(listener)
+        }
+        None => None,
+    };
+    let state = Arc::new(State::try_new(config).await?);
+    info!(
+        "Chat data is stored in {}, fan out messages with {}",
+        config.storage.data_dir.display(),
+        config.delivery.fanout
+    );
+    if args.console {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let peer_id = PeerId::next();
+            if let Err(e) = native::handle_console(state, peer_id, transport::console()).await {
+                warn!("Local console failed: {e}");
+            }
+            info!("Local console closed");
+        });
+    }
+    let interval = config.retention.compact_interval_mins;
+    if interval > 0 {
+        tokio::spawn(compact_periodically(
+            state.clone(),
+            Duration::from_secs(interval * 60),
+        ));
+    }
+    let unix_state = state.clone();
+    let unix = async move {
+        match unix_listener {
+            Some(listener) => serve(listener, unix_state, native::handle_request).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::try_join!(
+        serve(listener, state.clone(), native::handle_request),
+        unix,
+        serve(irc_listener, state, irc::handle_request),
+    )?;
+    Ok(())
+}
+
+/// 只读地重放日志，服务运行时也可以导出
+async fn run_export(
+    config: &Config,
+    format: Format,
+    output: Option<PathBuf>,
+    selection: Vec<String>,
+) -> anyhow::Result<()> {
+    //日志写到 stderr，避免和输出到 stdout 的聊天记录混在一起
+    let layer = Layer::new()
+        .with_writer(std::io::stderr)
+        .with_filter(LevelFilter::WARN);
+    tracing_subscriber::registry().with(layer).init();
+
+    let selection = Selection::parse(selection.iter().map(String::as_str))?;
+    let records = history::replay(&config.storage.data_dir.join("messages")).await?;
+    let transcript = export::export(records.values(), &selection, format)?;
+    match output {
+        Some(path) => fs::write(path, transcript).await?,
+        None => print!("{transcript}"),
+    }
+    Ok(())
+}
+
+/// 启动时先压缩一次
+async fn compact_periodically(state: Arc<State>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match state.compact().await {
+            Ok(compaction) if compaction.expired > 0 => info!("Compacted history: {compaction}"),
+            Ok(_) => {}
+            Err(e) => warn!("Fail to compact history: {e}"),
+        }
+    }
+}
+
+async fn serve<L, F, Fut>(listener: L, state: Arc<State>, handler: F) -> anyhow::Result<()>
+where
+    L: Listener,
+    F: Fn(Arc<State>, PeerId, L::Stream) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    loop {
+        let (socket, remote) = listener.accept().await?;
+        let state = state.clone();
+        let peer_id = PeerId::next();
+        info!("Accepted connection from {remote} as {peer_id}");
+        tokio::spawn(async move {
+            if let Err(e) = handler(state, peer_id, socket).await {
+                warn!("Can not handle {peer_id} from {remote}:{e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,51 @@
+/// 用户名允许的字符，和 @ 提及的解析规则保持一致
+pub fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// 找出内容中所有 `@username`，按出现顺序去重
+pub fn parse(content: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for (_, token) in tokens(content) {
+        let name = &token[1..];
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// 把已解析出的提及渲染成 `*@username*`，未解析的保持原样
+pub fn highlight(content: &str, mentions: &[String]) -> String {
+    if mentions.is_empty() {
+        return content.to_string();
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut last = 0;
+    for (start, token) in tokens(content) {
+        if mentions.iter().any(|m| m == &token[1..]) {
+            out.push_str(&content[last..start]);
+            out.push('*');
+            out.push_str(token);
+            out.push('*');
+            last = start + token.len();
+        }
+    }
+    out.push_str(&content[last..]);
+    out
+}
+
+/// `@` 前面必须是开头或者非用户名字符，避免把邮箱地址当成提及
+fn tokens(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content.char_indices().filter_map(move |(i, c)| {
+        if c != '@' {
+            return None;
+        }
+        if content[..i].chars().next_back().is_some_and(is_name_char) {
+            return None;
+        }
+        let rest = &content[i + 1..];
+        let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+        (len > 0).then(|| (i, &content[i..i + 1 + len]))
+    })
+}
@@ -0,0 +1,166 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+use crate::{history::ChatRecord, mailbox::Letter, mention, rooms::RoomChange};
+
+/// 发给 peer 的事件，由各个前端（行协议、IRC）自己决定如何渲染
+#[derive(Debug, Clone)]
+pub enum Message {
+    Joined {
+        room: String,
+        username: String,
+    },
+    Parted {
+        room: String,
+        username: String,
+    },
+    /// 断开连接，发给所有和该用户在同一个房间的人
+    Quit(String),
+    Chat(ChatRecord),
+    Edited(ChatRecord),
+    /// 单独发给被提及的用户
+    Mention(ChatRecord),
+    Deleted {
+        id: u64,
+        room: String,
+        by: String,
+    },
+    /// 私信，从信箱中投递的私信带有发送时间
+    Direct {
+        from: String,
+        content: String,
+        sent_at: Option<DateTime<Utc>>,
+    },
+    Topic {
+        room: String,
+        by: String,
+        topic: String,
+    },
+    Names {
+        room: String,
+        users: Vec<String>,
+    },
+    RoomChanged {
+        room: String,
+        by: String,
+        change: RoomChange,
+    },
+    /// 单独发给被邀请的用户
+    Invited {
+        room: String,
+        by: String,
+    },
+    Notice(String),
+    /// 已经按前端协议渲染好的一行，只发给单个 peer
+    Raw(String),
+}
+
+impl Message {
+    pub fn joined(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::Joined {
+            room: room.into(),
+            username: username.into(),
+        }
+    }
+
+    pub fn parted(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::Parted {
+            room: room.into(),
+            username: username.into(),
+        }
+    }
+
+    pub fn deleted(record: &ChatRecord, by: impl Into<String>) -> Self {
+        Self::Deleted {
+            id: record.id,
+            room: record.room.clone(),
+            by: by.into(),
+        }
+    }
+
+    pub fn direct(from: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Direct {
+            from: from.into(),
+            content: content.into(),
+            sent_at: None,
+        }
+    }
+
+    pub fn letter(letter: Letter) -> Self {
+        Self::Direct {
+            from: letter.from,
+            content: letter.content,
+            sent_at: Some(letter.sent_at),
+        }
+    }
+
+    pub fn notice(content: impl Into<String>) -> Self {
+        Self::Notice(content.into())
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Joined { room, username } => write!(f, "[{} has joined {}]", username, room),
+            Self::Parted { room, username } => write!(f, "[{} has left {}]", username, room),
+            Self::Quit(username) => write!(f, "[{} has left the chat :(]", username),
+            Self::Chat(record) => {
+                write!(f, "[{}] #{} {}", record.room, record.id, record.sender)?;
+                if let Some(parent) = record.reply_to {
+                    write!(f, " (re #{})", parent)?;
+                }
+                write!(
+                    f,
+                    ": {}",
+                    mention::highlight(&record.content, &record.mentions)
+                )?;
+                if record.edited_at.is_some() {
+                    write!(f, " (edited)")?;
+                }
+                Ok(())
+            }
+            Self::Edited(record) => write!(
+                f,
+                "[{}] [#{} edited] {}: {}",
+                record.room,
+                record.id,
+                record.sender,
+                mention::highlight(&record.content, &record.mentions)
+            ),
+            Self::Mention(record) => write!(
+                f,
+                "[mention] [{}] #{} {}: {}",
+                record.room,
+                record.id,
+                record.sender,
+                mention::highlight(&record.content, &record.mentions)
+            ),
+            Self::Deleted { id, room, by } => write!(f, "[{}] [#{} deleted by {}]", room, id, by),
+            Self::Direct {
+                from,
+                content,
+                sent_at: None,
+            } => write!(f, "[dm from {}] {}", from, content),
+            Self::Direct {
+                from,
+                content,
+                sent_at: Some(at),
+            } => write!(
+                f,
+                "[dm from {} at {}] {}",
+                from,
+                at.format("%Y-%m-%d %H:%M UTC"),
+                content
+            ),
+            Self::Topic { room, by, topic } => {
+                write!(f, "[{} set the topic of {}: {}]", by, room, topic)
+            }
+            Self::Names { room, users } => write!(f, "[users in {}: {}]", room, users.join(", ")),
+            Self::RoomChanged { room, by, change } => write!(f, "[{}: {} {}]", room, by, change),
+            Self::Invited { room, by } => write!(f, "[{} invited you to {}]", by, room),
+            Self::Notice(content) => write!(f, "[{}]", content),
+            Self::Raw(line) => write!(f, "{}", line),
+        }
+    }
+}
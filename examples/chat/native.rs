@@ -0,0 +1,399 @@
+/*
+行协议前端
+    - 每行一条消息，以 `/` 开头的是命令
+    - 登录成功后自动加入默认房间，普通消息发送到当前房间
+*/
+
+use chrono::Utc;
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::fs;
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{info, warn};
+
+use crate::{
+    accounts,
+    command::Command,
+    config,
+    error::ChatError,
+    export::export,
+    message::Message,
+    state::{normalize_room, validate_username, Delivery, PeerId, State},
+    transport::Connection,
+};
+
+#[derive(Debug)]
+struct Peer {
+    username: String,
+    operator: bool,
+    /// 当前房间，普通消息发送到这里
+    room: Option<String>,
+}
+
+pub async fn handle_request(
+    state: Arc<State>,
+    peer_id: PeerId,
+    stream: impl Connection,
+) -> anyhow::Result<()> {
+    handle_peer(state, peer_id, stream, false).await
+}
+
+/// 本地控制台：服务器的 stdin/stdout，登录后直接是 operator
+pub async fn handle_console(
+    state: Arc<State>,
+    peer_id: PeerId,
+    stream: impl Connection,
+) -> anyhow::Result<()> {
+    handle_peer(state, peer_id, stream, true).await
+}
+
+async fn handle_peer(
+    state: Arc<State>,
+    peer_id: PeerId,
+    stream: impl Connection,
+    operator: bool,
+) -> anyhow::Result<()> {
+    let mut encoder = Framed::new(stream, LinesCodec::new());
+    let Some(username) = login(&state, peer_id, &mut encoder).await? else {
+        return Ok(());
+    };
+    let guard = state.guard(peer_id);
+
+    let mut rx = state.add(peer_id, username.clone());
+    let (mut stream_sender, mut stream_receiver) = encoder.split();
+    //创建异步task，从channel中接收消息，并通过stream转发，写失败说明 client 已经断开
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if let Err(e) = stream_sender.send(message.to_string()).await {
+                warn!("Fail to send message to {peer_id}:{e}");
+                break;
+            }
+        }
+    });
+
+    let (letters, unread) = state.take_offline(&username).await?;
+    let mut welcome = format!("welcome {}", username);
+    if !letters.is_empty() {
+        welcome.push_str(&format!(", you have {} new messages", letters.len()));
+    }
+    if unread > 0 {
+        welcome.push_str(&format!(
+            ", you have {unread} unread mentions, use /mentions to read them"
+        ));
+    }
+    state
+        .send(peer_id, Arc::new(Message::notice(welcome)))
+        .await;
+    for letter in letters {
+        state.send(peer_id, Arc::new(Message::letter(letter))).await;
+    }
+
+    //用户加入默认房间时广播，默认房间满了或者只能邀请加入时留在房间外面，可以再 /join 其他房间
+    let mut peer = Peer {
+        username,
+        operator,
+        room: None,
+    };
+    let room = config::get().rooms.default.clone();
+    if let Err(e) = join(&state, peer_id, &mut peer, room, None).await {
+        state
+            .send(peer_id, Arc::new(Message::notice(e.to_string())))
+            .await;
+    }
+
+    while let Some(line) = stream_receiver.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to read line from {}: {}", peer_id, e);
+                break;
+            }
+        };
+        let result = match line.parse() {
+            Ok(command) => handle_command(&state, peer_id, &mut peer, command).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            state
+                .send(peer_id, Arc::new(Message::notice(e.to_string())))
+                .await;
+        }
+    }
+    // when while loop exit, peer has left the chat or line reading failed
+    // remove peer from state and notify others that a user has left
+    guard.remove().await;
+    Ok(())
+}
+
+/// 读取用户名，注册用户还需要验证密码，返回 None 表示登录失败或者 client 提前断开
+async fn login(
+    state: &State,
+    peer_id: PeerId,
+    framed: &mut Framed<impl Connection, LinesCodec>,
+) -> anyhow::Result<Option<String>> {
+    for _ in 0..config::get().auth.max_login_attempts {
+        framed.send("please enter your username:").await?;
+        let Some(username) = framed.next().await.transpose()? else {
+            return Ok(None);
+        };
+        let username = username.trim().to_string();
+        if let Err(e) = validate_username(&username) {
+            framed
+                .send(Message::notice(e.to_string()).to_string())
+                .await?;
+            continue;
+        }
+
+        if state.accounts.lock().await.is_registered(&username) {
+            framed.send("please enter your password:").await?;
+            let Some(password) = framed.next().await.transpose()? else {
+                return Ok(None);
+            };
+            if !state.login(&username, &password).await? {
+                warn!("Wrong password for {username} from {peer_id}");
+                framed
+                    .send(Message::notice("wrong password").to_string())
+                    .await?;
+                continue;
+            }
+        }
+
+        if !state.claim(&username, peer_id) {
+            let reason = format!("{username} is already online");
+            framed.send(Message::notice(reason).to_string()).await?;
+            continue;
+        }
+        return Ok(Some(username));
+    }
+    let reason = "too many failed attempts";
+    framed.send(Message::notice(reason).to_string()).await?;
+    Ok(None)
+}
+
+/// 加入房间并切换为当前房间，然后回复房间的用户列表
+async fn join(
+    state: &State,
+    peer_id: PeerId,
+    peer: &mut Peer,
+    room: String,
+    password: Option<&str>,
+) -> Result<(), ChatError> {
+    state.join(peer_id, &peer.username, &room, password).await?;
+    send_names(state, peer_id, room.clone()).await?;
+    peer.room = Some(room);
+    Ok(())
+}
+
+async fn send_names(state: &State, peer_id: PeerId, room: String) -> Result<(), ChatError> {
+    let users = state.names(&room)?;
+    let message = Message::Names { room, users };
+    state.send(peer_id, Arc::new(message)).await;
+    Ok(())
+}
+
+fn current_room(peer: &Peer) -> Result<&str, ChatError> {
+    peer.room.as_deref().ok_or(ChatError::NoRoom)
+}
+
+async fn handle_command(
+    state: &State,
+    peer_id: PeerId,
+    peer: &mut Peer,
+    command: Command,
+) -> Result<(), ChatError> {
+    match command {
+        Command::Chat(content) => {
+            let room = current_room(peer)?;
+            state.post(&peer.username, room, content, None).await?;
+        }
+        Command::Reply { parent, content } => {
+            let room = current_room(peer)?;
+            state
+                .post(&peer.username, room, content, Some(parent))
+                .await?;
+        }
+        Command::Edit { id, content } => {
+            state.edit(&peer.username, id, content).await?;
+        }
+        Command::Delete(id) => {
+            state.delete(&peer.username, id, peer.operator).await?;
+        }
+        Command::History(n) => {
+            let room = current_room(peer)?;
+            //先收集再发送，避免发送时一直持有锁
+            let messages: Vec<Message> = state
+                .history
+                .lock()
+                .await
+                .recent(room, n)
+                .into_iter()
+                .map(|record| match &record.deleted_by {
+                    Some(by) => Message::deleted(record, by),
+                    None => Message::Chat(record.clone()),
+                })
+                .collect();
+            for message in messages {
+                state.send(peer_id, Arc::new(message)).await;
+            }
+        }
+        Command::Join { room, password } => {
+            let room = normalize_room(&room)?;
+            join(state, peer_id, peer, room, password.as_deref()).await?;
+        }
+        Command::Part(room) => {
+            let room = match room {
+                Some(room) => normalize_room(&room)?,
+                None => current_room(peer)?.to_string(),
+            };
+            state.part(&peer.username, &room).await?;
+            if peer.room.as_deref() == Some(room.as_str()) {
+                peer.room = None;
+            }
+        }
+        Command::List => {
+            for room in state.rooms() {
+                let mut line = format!(
+                    "{} ({} users; {})",
+                    room.name,
+                    room.members,
+                    room.settings.describe()
+                );
+                if let Some(topic) = room.settings.topic {
+                    line.push_str(&format!(": {topic}"));
+                }
+                state.send(peer_id, Arc::new(Message::notice(line))).await;
+            }
+        }
+        Command::Topic(None) => {
+            let room = current_room(peer)?;
+            let line = match state.topic(room)? {
+                Some(topic) => format!("topic of {room}: {topic}"),
+                None => format!("{room} has no topic"),
+            };
+            state.send(peer_id, Arc::new(Message::notice(line))).await;
+        }
+        Command::Topic(Some(topic)) => {
+            let room = current_room(peer)?;
+            state
+                .set_topic(&peer.username, room, topic, peer.operator)
+                .await?;
+        }
+        Command::Who => {
+            let room = current_room(peer)?.to_string();
+            let settings = state.room(&room)?.settings.describe();
+            send_names(state, peer_id, room.clone()).await?;
+            let message = Message::notice(format!("{room}: {settings}"));
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Invite(target) => {
+            let room = current_room(peer)?;
+            state
+                .invite(&peer.username, room, &target, peer.operator)
+                .await?;
+            let message = Message::notice(format!("{target} is invited to {room}"));
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Configure(change) => {
+            let room = current_room(peer)?;
+            state
+                .configure(&peer.username, room, change, peer.operator)
+                .await?;
+        }
+        Command::Oper(password) => {
+            match &config::get().auth.oper_password {
+                Some(expected) if accounts::same_secret(expected, &password) => {
+                    peer.operator = true
+                }
+                _ => return Err(ChatError::WrongPassword),
+            }
+            info!("{} is now an operator", peer.username);
+            let message = Message::notice("you are now an operator");
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Register(password) => {
+            state.register(&peer.username, &password).await?;
+            info!("{} has registered", peer.username);
+            let message = Message::notice(format!(
+                "{} is registered, you will need the password to log in next time",
+                peer.username
+            ));
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Mentions => {
+            let messages: Vec<Message> = state
+                .take_mentions(&peer.username)
+                .await?
+                .into_iter()
+                .map(Message::Chat)
+                .collect();
+            if messages.is_empty() {
+                let message = Message::notice("no unread mentions");
+                state.send(peer_id, Arc::new(message)).await;
+            }
+            for message in messages {
+                state.send(peer_id, Arc::new(message)).await;
+            }
+        }
+        Command::Search(query) => {
+            let (total, records) = state.search(&peer.username, &query).await;
+            let pages = total.div_ceil(config::get().limits.search_page_size);
+            let mut summary = format!(
+                "{total} results for \"{}\", page {}/{}",
+                query.terms,
+                query.page,
+                pages.max(1)
+            );
+            if query.page < pages {
+                summary.push_str(&format!(", use page:{} for more", query.page + 1));
+            }
+            state
+                .send(peer_id, Arc::new(Message::notice(summary)))
+                .await;
+            for record in records {
+                state.send(peer_id, Arc::new(Message::Chat(record))).await;
+            }
+        }
+        Command::Export { format, selection } => {
+            if !peer.operator {
+                return Err(ChatError::NotOperator);
+            }
+            let history = state.history.lock().await;
+            let transcript = export(history.records(), &selection, format)?;
+            drop(history);
+            let dir = config::get().storage.data_dir.join("exports");
+            fs::create_dir_all(&dir).await?;
+            let name = format!(
+                "transcript-{}.{}",
+                Utc::now().format("%Y%m%dT%H%M%S"),
+                format.extension()
+            );
+            let path = dir.join(name);
+            fs::write(&path, transcript).await?;
+            info!(
+                "{} exported a transcript to {}",
+                peer.username,
+                path.display()
+            );
+            let message = Message::notice(format!("transcript written to {}", path.display()));
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Compact => {
+            if !peer.operator {
+                return Err(ChatError::NotOperator);
+            }
+            let compaction = state.compact().await?;
+            info!("{} compacted the history: {compaction}", peer.username);
+            let message = Message::notice(format!("compaction done: {compaction}"));
+            state.send(peer_id, Arc::new(message)).await;
+        }
+        Command::Msg { to, content } => {
+            if let Delivery::Mailbox = state.direct(&peer.username, &to, content).await? {
+                let message = Message::notice(format!(
+                    "{to} is offline, the message will be delivered at their next login"
+                ));
+                state.send(peer_id, Arc::new(message)).await;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+/*
+房间设置
+    - 创建房间的人是房主，房主可以任命和撤销房间管理员
+    - 房主和管理员可以修改话题和设置、邀请用户
+    - 设置：仅限邀请、加入密码、人数上限、公告模式（只有房主和管理员可以发言）
+    - 默认房间和房主是注册用户的房间会持久化，访客创建的房间在没人时删除
+*/
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt};
+
+use crate::error::ChatError;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// 默认房间没有房主
+    pub owner: Option<String>,
+    pub operators: BTreeSet<String>,
+    pub topic: Option<String>,
+    pub invite_only: bool,
+    pub password: Option<String>,
+    pub max_members: Option<usize>,
+    /// 公告模式，只有房主和管理员可以发言
+    pub announce: bool,
+    /// 仅限邀请时被邀请的用户，加入后移除
+    pub invited: BTreeSet<String>,
+}
+
+/// 房主和管理员对房间的修改
+#[derive(Debug, Clone)]
+pub enum RoomChange {
+    Op(String),
+    Deop(String),
+    InviteOnly(bool),
+    Password(Option<String>),
+    MaxMembers(Option<usize>),
+    Announce(bool),
+}
+
+impl RoomSettings {
+    pub fn owned_by(owner: &str) -> Self {
+        Self {
+            owner: Some(owner.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_owner(&self, username: &str) -> bool {
+        self.owner.as_deref() == Some(username)
+    }
+
+    pub fn is_operator(&self, username: &str) -> bool {
+        self.is_owner(username) || self.operators.contains(username)
+    }
+
+    /// 检查能否加入，房主和管理员不受设置限制
+    pub fn check_join(
+        &self,
+        room: &str,
+        username: &str,
+        password: Option<&str>,
+        members: usize,
+    ) -> Result<(), ChatError> {
+        if self.is_operator(username) {
+            return Ok(());
+        }
+        if self.invite_only && !self.invited.contains(username) {
+            return Err(ChatError::InviteOnly(room.to_string()));
+        }
+        if self.password.is_some() && self.password.as_deref() != password {
+            return Err(ChatError::BadRoomPassword(room.to_string()));
+        }
+        if self.max_members.is_some_and(|max| members >= max) {
+            return Err(ChatError::RoomFull(room.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, change: &RoomChange) {
+        match change {
+            RoomChange::Op(username) => {
+                self.operators.insert(username.clone());
+            }
+            RoomChange::Deop(username) => {
+                self.operators.remove(username);
+            }
+            RoomChange::InviteOnly(on) => self.invite_only = *on,
+            RoomChange::Password(password) => self.password = password.clone(),
+            RoomChange::MaxMembers(max) => self.max_members = *max,
+            RoomChange::Announce(on) => self.announce = *on,
+        }
+    }
+
+    /// 用于 /list 和 /who 的简短描述，不包含密码本身
+    pub fn describe(&self) -> String {
+        let mut parts = vec![match &self.owner {
+            Some(owner) => format!("owner {owner}"),
+            None => "no owner".to_string(),
+        }];
+        if !self.operators.is_empty() {
+            let operators: Vec<&str> = self.operators.iter().map(String::as_str).collect();
+            parts.push(format!("operators {}", operators.join(" ")));
+        }
+        if self.invite_only {
+            parts.push("invite-only".to_string());
+        }
+        if self.password.is_some() {
+            parts.push("password".to_string());
+        }
+        if let Some(max) = self.max_members {
+            parts.push(format!("max {max} members"));
+        }
+        if self.announce {
+            parts.push("announce".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+impl RoomChange {
+    /// 任命和撤销管理员只有房主可以做
+    pub fn needs_owner(&self) -> bool {
+        matches!(self, Self::Op(_) | Self::Deop(_))
+    }
+
+    /// 解析 `/set <name> <value>`
+    pub fn parse_setting(name: &str, value: &str) -> Option<Self> {
+        let switch = match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+        match name {
+            "invite-only" => Some(Self::InviteOnly(switch?)),
+            "announce" => Some(Self::Announce(switch?)),
+            "password" if value == "off" => Some(Self::Password(None)),
+            "password" if !value.is_empty() => Some(Self::Password(Some(value.to_string()))),
+            "max-members" if value == "off" => Some(Self::MaxMembers(None)),
+            "max-members" => value
+                .parse()
+                .ok()
+                .filter(|max| *max > 0)
+                .map(|max| Self::MaxMembers(Some(max))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RoomChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let switch = |on: &bool| if *on { "on" } else { "off" };
+        match self {
+            Self::Op(username) => write!(f, "made {} an operator", username),
+            Self::Deop(username) => write!(f, "removed {} from operators", username),
+            Self::InviteOnly(on) => write!(f, "set invite-only {}", switch(on)),
+            Self::Password(Some(_)) => write!(f, "set a password"),
+            Self::Password(None) => write!(f, "removed the password"),
+            Self::MaxMembers(Some(max)) => write!(f, "set max-members {}", max),
+            Self::MaxMembers(None) => write!(f, "removed the member limit"),
+            Self::Announce(on) => write!(f, "set announce {}", switch(on)),
+        }
+    }
+}
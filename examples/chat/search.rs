@@ -0,0 +1,175 @@
+/*
+全文搜索
+    - 倒排索引：词到消息 ID 的集合，随聊天记录一起更新，编辑后重新索引，删除后移除
+    - 英文等按字母数字切词并转成小写，中日韩文字同时索引单字和相邻两个字
+    - 查询中的所有词都要出现，结果按时间倒序分页
+*/
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{error::ChatError, history::ChatRecord, state::normalize_room};
+
+const SEARCH_USAGE: &str = "/search <terms> [in:room] [from:user] [page:n]";
+
+#[derive(Debug, Default)]
+pub struct Index {
+    postings: HashMap<String, BTreeSet<u64>>,
+    /// 每条消息的词，重新索引时用来清理旧的倒排项
+    tokens: HashMap<u64, BTreeSet<String>>,
+}
+
+/// `/search <terms> [in:room] [from:user] [page:n]`
+#[derive(Debug)]
+pub struct SearchQuery {
+    pub terms: String,
+    pub tokens: Vec<String>,
+    pub room: Option<String>,
+    pub from: Option<String>,
+    /// 从 1 开始
+    pub page: usize,
+}
+
+impl Index {
+    pub fn build<'a>(records: impl IntoIterator<Item = &'a ChatRecord>) -> Self {
+        let mut index = Self::default();
+        for record in records {
+            index.update(record);
+        }
+        index
+    }
+
+    pub fn update(&mut self, record: &ChatRecord) {
+        self.remove(record.id);
+        if record.deleted_by.is_some() {
+            return;
+        }
+        let tokens: BTreeSet<String> = tokenize(&record.content).into_iter().collect();
+        for token in &tokens {
+            self.postings
+                .entry(token.clone())
+                .or_default()
+                .insert(record.id);
+        }
+        self.tokens.insert(record.id, tokens);
+    }
+
+    /// 包含所有词的消息 ID，从新到旧
+    pub fn search(&self, tokens: &[String]) -> Vec<u64> {
+        let mut sets: Vec<&BTreeSet<u64>> = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match self.postings.get(token) {
+                Some(ids) => sets.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        //从最小的集合开始求交集
+        sets.sort_by_key(|ids| ids.len());
+        let Some((first, rest)) = sets.split_first() else {
+            return Vec::new();
+        };
+        first
+            .iter()
+            .rev()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .copied()
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let Some(tokens) = self.tokens.remove(&id) else {
+            return;
+        };
+        for token in tokens {
+            if let Some(ids) = self.postings.get_mut(&token) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+}
+
+impl SearchQuery {
+    pub fn parse(args: &str) -> Result<Self, ChatError> {
+        let mut terms = Vec::new();
+        let mut query = Self {
+            terms: String::new(),
+            tokens: Vec::new(),
+            room: None,
+            from: None,
+            page: 1,
+        };
+        for arg in args.split_whitespace() {
+            if let Some(room) = arg.strip_prefix("in:") {
+                query.room = Some(normalize_room(room)?);
+            } else if let Some(from) = arg.strip_prefix("from:") {
+                query.from = Some(from.to_string());
+            } else if let Some(page) = arg.strip_prefix("page:") {
+                query.page = page
+                    .parse()
+                    .ok()
+                    .filter(|page| *page > 0)
+                    .ok_or(ChatError::Usage(SEARCH_USAGE))?;
+            } else {
+                terms.push(arg);
+            }
+        }
+        query.terms = terms.join(" ");
+        query.tokens = tokenize(&query.terms);
+        query.tokens.sort();
+        query.tokens.dedup();
+        if query.tokens.is_empty() {
+            return Err(ChatError::Usage(SEARCH_USAGE));
+        }
+        Ok(query)
+    }
+
+    pub fn matches(&self, record: &ChatRecord) -> bool {
+        self.room.as_ref().is_none_or(|room| *room == record.room)
+            && self.from.as_ref().is_none_or(|from| *from == record.sender)
+    }
+}
+
+/// 中日韩文字没有空格分词，索引单字和相邻两个字，查询时同样切分
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut previous: Option<char> = None;
+    for c in text.chars() {
+        if is_cjk(c) {
+            flush(&mut word, &mut tokens);
+            tokens.push(c.to_string());
+            if let Some(previous) = previous {
+                tokens.push(format!("{previous}{c}"));
+            }
+            previous = Some(c);
+            continue;
+        }
+        previous = None;
+        if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else {
+            flush(&mut word, &mut tokens);
+        }
+    }
+    flush(&mut word, &mut tokens);
+    tokens
+}
+
+fn flush(word: &mut String, tokens: &mut Vec<String>) {
+    if !word.is_empty() {
+        tokens.push(std::mem::take(word));
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'     // 平假名、片假名
+        | '\u{3400}'..='\u{4dbf}'   // 扩展 A
+        | '\u{4e00}'..='\u{9fff}'   // 基本汉字
+        | '\u{ac00}'..='\u{d7af}'   // 韩文音节
+        | '\u{f900}'..='\u{faff}'   // 兼容汉字
+        | '\u{20000}'..='\u{2a6df}' // 扩展 B
+    )
+}
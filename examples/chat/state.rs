@@ -0,0 +1,726 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    accounts::{self, Accounts},
+    config::{self, Config},
+    error::ChatError,
+    fanout::{Fanout, Inbox},
+    filter::ContentFilter,
+    history::{self, ChatRecord, Compaction, History},
+    mailbox::{Letter, Mailbox},
+    mention,
+    message::Message,
+    rooms::{RoomChange, RoomSettings},
+    search::SearchQuery,
+    storage::{load_json, save_json},
+    webhook::{Event, Webhooks},
+};
+
+/// 聊天服务的核心状态，和具体的前端协议无关
+#[derive(Debug)]
+pub struct State {
+    /// 在线 peer 的 ID 到用户名
+    peers: DashMap<PeerId, String>,
+    fanout: Fanout,
+    filter: ContentFilter,
+    /// 在线用户名到 peer 的映射，保证用户名唯一
+    names: DashMap<String, PeerId>,
+    rooms: DashMap<String, Room>,
+    /// 保存房间设置时持有，避免并发写同一个临时文件
+    rooms_path: Mutex<PathBuf>,
+    pub history: Mutex<History>,
+    /// 同一时间只有一个压缩任务重写日志段
+    compacting: Mutex<()>,
+    pub accounts: Mutex<Accounts>,
+    pub mailbox: Mutex<Mailbox>,
+    webhooks: Webhooks,
+}
+
+/// 一个连接的 ID，和传输方式（TCP、Unix domain socket、本地控制台）无关
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(u64);
+
+/// 占用用户名之后由前端持有，drop 时把 peer 从 state 中删除，前端出错提前返回也不会一直占着用户名
+#[derive(Debug)]
+pub struct PeerGuard {
+    state: Arc<State>,
+    peer_id: PeerId,
+    removed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Room {
+    settings: RoomSettings,
+    /// 用户名到 peer，按用户名排序
+    members: BTreeMap<String, PeerId>,
+}
+
+#[derive(Debug)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub settings: RoomSettings,
+}
+
+/// 私信的投递结果
+pub enum Delivery {
+    Online,
+    Mailbox,
+}
+
+impl PeerGuard {
+    /// 正常断开时等待删除完成，之后离开的通知已经发出
+    pub async fn remove(mut self) {
+        self.removed = true;
+        self.state.remove(self.peer_id).await;
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+        //服务关闭时没有 runtime，也就不需要清理了
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let state = self.state.clone();
+        let peer_id = self.peer_id;
+        runtime.spawn(async move { state.remove(peer_id).await });
+    }
+}
+
+impl PeerId {
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer {}", self.0)
+    }
+}
+
+impl State {
+    pub async fn try_new(config: &Config) -> anyhow::Result<Self> {
+        let data_dir = config.storage.data_dir.as_path();
+        let rooms_path = data_dir.join("rooms.json");
+        let settings: BTreeMap<String, RoomSettings> = load_json(&rooms_path).await?;
+        let rooms: DashMap<String, Room> = settings
+            .into_iter()
+            .map(|(name, settings)| {
+                let room = Room {
+                    settings,
+                    members: BTreeMap::new(),
+                };
+                (name, room)
+            })
+            .collect();
+        rooms.entry(config.rooms.default.clone()).or_default();
+        Ok(Self {
+            peers: DashMap::new(),
+            fanout: Fanout::new(&config.delivery),
+            filter: ContentFilter::new(&config.filters)?,
+            names: DashMap::new(),
+            rooms,
+            rooms_path: Mutex::new(rooms_path),
+            history: Mutex::new(
+                History::open(data_dir.join("messages"), config.storage.segment_bytes).await?,
+            ),
+            compacting: Mutex::new(()),
+            accounts: Mutex::new(Accounts::open(data_dir.join("accounts.json")).await?),
+            mailbox: Mutex::new(
+                Mailbox::open(
+                    data_dir.join("mailbox.json"),
+                    config.limits.mailbox_quota,
+                    chrono::Duration::days(config.limits.mailbox_ttl_days.into()),
+                )
+                .await?,
+            ),
+            webhooks: Webhooks::open(data_dir.join("webhooks.json")).await?,
+        })
+    }
+
+    /// 占用用户名，已经有同名用户在线时返回 false
+    pub fn claim(&self, username: &str, peer_id: PeerId) -> bool {
+        match self.names.entry(username.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(peer_id);
+                true
+            }
+        }
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.names.contains_key(username)
+    }
+
+    /// 登录成功后加入 state，返回的收件箱由前端负责渲染并写回 client
+    pub fn add(&self, peer_id: PeerId, username: String) -> Inbox {
+        let inbox = self.fanout.subscribe(peer_id);
+        self.peers.insert(peer_id, username);
+        inbox
+    }
+
+    /// 在 `claim` 成功之后立刻调用
+    pub fn guard(self: &Arc<Self>, peer_id: PeerId) -> PeerGuard {
+        PeerGuard {
+            state: self.clone(),
+            peer_id,
+            removed: false,
+        }
+    }
+
+    /// 断开连接：离开所有房间，通知同房间的人，释放用户名
+    pub async fn remove(&self, peer_id: PeerId) {
+        let username = self.peers.remove(&peer_id).map(|(_, username)| username);
+        let mut neighbours = HashSet::new();
+        let mut left = Vec::new();
+        for mut room in self.rooms.iter_mut() {
+            if room.members.values().any(|a| *a == peer_id) {
+                room.members.retain(|_, a| *a != peer_id);
+                neighbours.extend(room.members.values().copied());
+                left.push(room.key().clone());
+            }
+        }
+        for room in left {
+            self.drop_if_empty(&room).await;
+            if let Some(username) = &username {
+                self.webhooks.emit(Event::Part {
+                    room,
+                    username: username.clone(),
+                });
+            }
+        }
+
+        self.fanout.unsubscribe(peer_id);
+        self.names.retain(|_, other| *other != peer_id);
+
+        if let Some(username) = username {
+            let mut accounts = self.accounts.lock().await;
+            if !accounts.is_registered(&username) {
+                accounts.forget_guest(&username);
+            }
+            drop(accounts);
+
+            let message = Arc::new(Message::Quit(username));
+            info!("{}", message);
+            self.send_all(neighbours, message).await;
+        }
+    }
+
+    /// 登录时取出离线信箱中的私信，以及未读提及的数量
+    pub async fn take_offline(&self, username: &str) -> Result<(Vec<Letter>, usize), ChatError> {
+        let unread = self.accounts.lock().await.unread_mentions(username);
+        let letters = self.mailbox.lock().await.take(username).await?;
+        Ok((letters, unread))
+    }
+
+    pub async fn send_to_user(&self, username: &str, message: Arc<Message>) {
+        let Some(peer_id) = self.names.get(username).map(|peer_id| *peer_id) else {
+            return;
+        };
+        self.send(peer_id, message).await;
+    }
+
+    pub async fn send(&self, peer_id: PeerId, message: Arc<Message>) {
+        self.fanout.send(peer_id, message).await;
+    }
+
+    /// 广播给房间里的所有人，包括发送者自己
+    pub async fn broadcast(&self, room: &str, message: Arc<Message>) {
+        let members: Vec<PeerId> = match self.rooms.get(room) {
+            Some(room) => room.members.values().copied().collect(),
+            None => return,
+        };
+        self.send_all(members, message).await;
+    }
+
+    async fn send_all(&self, peer_ids: impl IntoIterator<Item = PeerId>, message: Arc<Message>) {
+        self.fanout.send_all(peer_ids, message).await;
+    }
+
+    /// 加入房间，房间不存在时创建并成为房主，返回 false 表示已经在房间里
+    pub async fn join(
+        &self,
+        peer_id: PeerId,
+        username: &str,
+        room: &str,
+        password: Option<&str>,
+    ) -> Result<bool, ChatError> {
+        let mut created = false;
+        let mut entry = self.rooms.entry(room.to_string()).or_insert_with(|| {
+            created = true;
+            Room {
+                settings: RoomSettings::owned_by(username),
+                members: BTreeMap::new(),
+            }
+        });
+        if entry.members.contains_key(username) {
+            return Ok(false);
+        }
+        entry
+            .settings
+            .check_join(room, username, password, entry.members.len())?;
+        //邀请只能使用一次
+        let invited = entry.settings.invited.remove(username);
+        entry.members.insert(username.to_string(), peer_id);
+        drop(entry);
+        self.broadcast(room, Arc::new(Message::joined(room, username)))
+            .await;
+        self.webhooks.emit(Event::Join {
+            room: room.to_string(),
+            username: username.to_string(),
+        });
+        if created || invited {
+            self.save_rooms().await?;
+        }
+        Ok(true)
+    }
+
+    /// 离开房间，最后一个人离开时删除房间（默认房间除外）
+    pub async fn part(&self, username: &str, room: &str) -> Result<(), ChatError> {
+        //先广播再移除，离开的人自己也能收到通知
+        self.check_member(username, room)?;
+        self.broadcast(room, Arc::new(Message::parted(room, username)))
+            .await;
+        if let Some(mut entry) = self.rooms.get_mut(room) {
+            entry.members.remove(username);
+        }
+        self.drop_if_empty(room).await;
+        self.webhooks.emit(Event::Part {
+            room: room.to_string(),
+            username: username.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn is_member(&self, username: &str, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|room| room.members.contains_key(username))
+    }
+
+    /// 区分房间不存在和不在房间里，IRC 前端对两者的回复不同
+    fn check_member(&self, username: &str, room: &str) -> Result<(), ChatError> {
+        match self.rooms.get(room) {
+            Some(entry) if entry.members.contains_key(username) => Ok(()),
+            Some(_) => Err(ChatError::NotInRoom(room.to_string())),
+            None => Err(ChatError::NoSuchRoom(room.to_string())),
+        }
+    }
+
+    /// 房间的用户列表，房主和管理员和 IRC 一样加上 `@` 前缀
+    pub fn names(&self, room: &str) -> Result<Vec<String>, ChatError> {
+        match self.rooms.get(room) {
+            Some(room) => Ok(room
+                .members
+                .keys()
+                .map(|name| match room.settings.is_operator(name) {
+                    true => format!("@{name}"),
+                    false => name.clone(),
+                })
+                .collect()),
+            None => Err(ChatError::NoSuchRoom(room.to_string())),
+        }
+    }
+
+    pub fn topic(&self, room: &str) -> Result<Option<String>, ChatError> {
+        match self.rooms.get(room) {
+            Some(room) => Ok(room.settings.topic.clone()),
+            None => Err(ChatError::NoSuchRoom(room.to_string())),
+        }
+    }
+
+    /// 只有房主、房间管理员和 operator 可以设置话题
+    pub async fn set_topic(
+        &self,
+        username: &str,
+        room: &str,
+        topic: String,
+        operator: bool,
+    ) -> Result<(), ChatError> {
+        self.check_member(username, room)?;
+        let topic = self.filter.apply(Some(room), room, &topic)?;
+        if let Some(mut entry) = self.rooms.get_mut(room) {
+            if !operator && !entry.settings.is_operator(username) {
+                return Err(ChatError::NotRoomOperator(room.to_string()));
+            }
+            entry.settings.topic = Some(topic.clone());
+        }
+        self.save_rooms().await?;
+        let message = Message::Topic {
+            room: room.to_string(),
+            by: username.to_string(),
+            topic,
+        };
+        self.broadcast(room, Arc::new(message)).await;
+        Ok(())
+    }
+
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|room| RoomInfo {
+                name: room.key().clone(),
+                members: room.members.len(),
+                settings: room.settings.clone(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    pub fn room(&self, room: &str) -> Result<RoomInfo, ChatError> {
+        match self.rooms.get(room) {
+            Some(entry) => Ok(RoomInfo {
+                name: room.to_string(),
+                members: entry.members.len(),
+                settings: entry.settings.clone(),
+            }),
+            None => Err(ChatError::NoSuchRoom(room.to_string())),
+        }
+    }
+
+    /// 修改房间设置，任命和撤销管理员需要房主，其余需要房间管理员，operator 不受限制
+    pub async fn configure(
+        &self,
+        username: &str,
+        room: &str,
+        change: RoomChange,
+        operator: bool,
+    ) -> Result<(), ChatError> {
+        {
+            let Some(mut entry) = self.rooms.get_mut(room) else {
+                return Err(ChatError::NoSuchRoom(room.to_string()));
+            };
+            let settings = &mut entry.settings;
+            if !operator && change.needs_owner() && !settings.is_owner(username) {
+                return Err(ChatError::NotRoomOwner(room.to_string()));
+            }
+            if !operator && !settings.is_operator(username) {
+                return Err(ChatError::NotRoomOperator(room.to_string()));
+            }
+            settings.apply(&change);
+        }
+        self.save_rooms().await?;
+        info!("{username} changed {room}: {change}");
+        let message = Message::RoomChanged {
+            room: room.to_string(),
+            by: username.to_string(),
+            change,
+        };
+        self.broadcast(room, Arc::new(message)).await;
+        Ok(())
+    }
+
+    /// 邀请用户加入房间，被邀请的用户在线时会收到通知
+    pub async fn invite(
+        &self,
+        username: &str,
+        room: &str,
+        target: &str,
+        operator: bool,
+    ) -> Result<(), ChatError> {
+        validate_username(target)?;
+        {
+            let Some(mut entry) = self.rooms.get_mut(room) else {
+                return Err(ChatError::NoSuchRoom(room.to_string()));
+            };
+            if !operator && !entry.settings.is_operator(username) {
+                return Err(ChatError::NotRoomOperator(room.to_string()));
+            }
+            entry.settings.invited.insert(target.to_string());
+        }
+        self.save_rooms().await?;
+        let message = Message::Invited {
+            room: room.to_string(),
+            by: username.to_string(),
+        };
+        self.send_to_user(target, Arc::new(message)).await;
+        Ok(())
+    }
+
+    /// 只搜索自己所在的房间和公开的房间，返回结果总数和指定页的结果
+    pub async fn search(&self, username: &str, query: &SearchQuery) -> (usize, Vec<ChatRecord>) {
+        let history = self.history.lock().await;
+        let hits: Vec<&ChatRecord> = history
+            .search(query)
+            .filter(|record| self.can_read(username, &record.room))
+            .collect();
+        let page_size = config::get().limits.search_page_size;
+        let records = hits
+            .iter()
+            .skip((query.page - 1) * page_size)
+            .take(page_size)
+            .map(|record| (*record).clone())
+            .collect();
+        (hits.len(), records)
+    }
+
+    fn can_read(&self, username: &str, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|entry| {
+            entry.members.contains_key(username)
+                || (!entry.settings.invite_only && entry.settings.password.is_none())
+        })
+    }
+
+    /// 取出并清空未读的提及，提及之后离开或者被移出的房间的消息不再返回
+    pub async fn take_mentions(&self, username: &str) -> Result<Vec<ChatRecord>, ChatError> {
+        let ids = self.accounts.lock().await.take_mentions(username).await?;
+        let history = self.history.lock().await;
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| history.get(id).ok())
+            .filter(|record| self.can_read(username, &record.room))
+            .cloned()
+            .collect())
+    }
+
+    /// 注册后该用户创建的房间也需要持久化
+    pub async fn register(&self, username: &str, password: &str) -> Result<(), ChatError> {
+        let password_hash = accounts::hash_password(password).await?;
+        self.accounts
+            .lock()
+            .await
+            .register(username, password_hash)
+            .await?;
+        self.save_rooms().await
+    }
+
+    /// 校验注册用户的密码，哈希在锁外面计算，不会阻塞其他用户登录
+    pub async fn login(&self, username: &str, password: &str) -> Result<bool, ChatError> {
+        let Some(stored) = self.accounts.lock().await.password(username) else {
+            return Ok(false);
+        };
+        let legacy = stored.is_legacy();
+        if !accounts::verify_password(stored, password).await {
+            return Ok(false);
+        }
+        if legacy {
+            let password_hash = accounts::hash_password(password).await?;
+            self.accounts
+                .lock()
+                .await
+                .upgrade_password(username, password_hash)
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// 发送聊天消息：写入聊天记录，广播到房间，通知被提及的用户
+    pub async fn post(
+        &self,
+        username: &str,
+        room: &str,
+        content: String,
+        reply_to: Option<u64>,
+    ) -> Result<ChatRecord, ChatError> {
+        self.check_member(username, room)?;
+        if let Some(entry) = self.rooms.get(room) {
+            if entry.settings.announce && !entry.settings.is_operator(username) {
+                return Err(ChatError::ReadOnlyRoom(room.to_string()));
+            }
+        }
+        let content = self.filter.apply(Some(room), room, &content)?;
+        let mentions = self.resolve_mentions(username, room, &content).await;
+        let record = self
+            .history
+            .lock()
+            .await
+            .post(room, username, content, reply_to, mentions)
+            .await?;
+        self.broadcast(room, Arc::new(Message::Chat(record.clone())))
+            .await;
+        self.webhooks.emit(Event::Message(record.clone()));
+        self.notify_mentions(&record, &record.mentions).await?;
+        Ok(record)
+    }
+
+    pub async fn edit(&self, username: &str, id: u64, content: String) -> Result<(), ChatError> {
+        let room = self.history.lock().await.get(id)?.room.clone();
+        let content = self.filter.apply(Some(&room), &room, &content)?;
+        let mentions = self.resolve_mentions(username, &room, &content).await;
+        let (record, added) = self
+            .history
+            .lock()
+            .await
+            .edit(id, username, content, mentions)
+            .await?;
+        self.broadcast(&record.room, Arc::new(Message::Edited(record.clone())))
+            .await;
+        self.notify_mentions(&record, &added).await
+    }
+
+    pub async fn delete(&self, username: &str, id: u64, operator: bool) -> Result<(), ChatError> {
+        let record = self
+            .history
+            .lock()
+            .await
+            .delete(id, username, operator)
+            .await?;
+        info!("Message #{id} deleted by {username}");
+        let message = Message::deleted(&record, username);
+        self.broadcast(&record.room, Arc::new(message)).await;
+        Ok(())
+    }
+
+    /// 按保留策略删除过期的消息并重写日志，重写日志段时不阻塞发送消息
+    pub async fn compact(&self) -> Result<Compaction, ChatError> {
+        let _compacting = self.compacting.lock().await;
+        let (expired, segments) = self
+            .history
+            .lock()
+            .await
+            .begin_compaction(&config::get().retention, chrono::Utc::now())
+            .await?;
+        history::compact(&segments, &expired).await
+    }
+
+    /// 私信：在线时直接发送，注册用户不在线时存入离线信箱
+    pub async fn direct(
+        &self,
+        from: &str,
+        to: &str,
+        content: String,
+    ) -> Result<Delivery, ChatError> {
+        if to == from {
+            return Err(ChatError::SelfMessage);
+        }
+        let content = self.filter.apply(None, to, &content)?;
+        if self.is_online(to) {
+            let message = Message::direct(from, content);
+            self.send_to_user(to, Arc::new(message)).await;
+            return Ok(Delivery::Online);
+        }
+        if !self.accounts.lock().await.is_registered(to) {
+            return Err(ChatError::UserNotFound(to.to_string()));
+        }
+        let letter = Letter {
+            from: from.to_string(),
+            content,
+            sent_at: chrono::Utc::now(),
+        };
+        self.mailbox.lock().await.deliver(to, letter).await?;
+        Ok(Delivery::Mailbox)
+    }
+
+    /// 没人的房间只有需要持久化时才保留
+    async fn drop_if_empty(&self, room: &str) {
+        let settings = match self.rooms.get(room) {
+            Some(entry) if entry.members.is_empty() => entry.settings.clone(),
+            _ => return,
+        };
+        if is_persistent(room, &settings, &*self.accounts.lock().await) {
+            return;
+        }
+        self.rooms
+            .remove_if(room, |_, entry| entry.members.is_empty());
+    }
+
+    async fn save_rooms(&self) -> Result<(), ChatError> {
+        let path = self.rooms_path.lock().await;
+        let accounts = self.accounts.lock().await;
+        let rooms: BTreeMap<String, RoomSettings> = self
+            .rooms
+            .iter()
+            .filter(|room| is_persistent(room.key(), &room.settings, &accounts))
+            .map(|room| (room.key().clone(), room.settings.clone()))
+            .collect();
+        drop(accounts);
+        save_json(&path, &rooms).await
+    }
+
+    /// 只保留能看到这个房间的在线用户和注册用户，不包括发送者自己
+    async fn resolve_mentions(&self, sender: &str, room: &str, content: &str) -> Vec<String> {
+        let names = mention::parse(content);
+        if names.is_empty() {
+            return Vec::new();
+        }
+        let accounts = self.accounts.lock().await;
+        names
+            .into_iter()
+            .filter(|name| *name != sender)
+            .filter(|name| self.is_online(name) || accounts.is_registered(name))
+            .filter(|name| self.can_read(name, room))
+            .map(String::from)
+            .collect()
+    }
+
+    /// 记录到被提及用户的未读列表，在线的用户同时收到单独的提及事件
+    async fn notify_mentions(
+        &self,
+        record: &ChatRecord,
+        targets: &[String],
+    ) -> Result<(), ChatError> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let message = Arc::new(Message::Mention(record.clone()));
+        for target in targets {
+            self.accounts
+                .lock()
+                .await
+                .add_mention(target, record.id)
+                .await?;
+            self.send_to_user(target, message.clone()).await;
+            self.webhooks.emit(Event::Mention {
+                user: target.clone(),
+                message: record.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 只保存默认房间和房主是注册用户的房间，访客创建的房间没人时删除
+fn is_persistent(room: &str, settings: &RoomSettings, accounts: &Accounts) -> bool {
+    room == config::get().rooms.default
+        || settings
+            .owner
+            .as_deref()
+            .is_some_and(|owner| accounts.is_registered(owner))
+}
+
+/// 房间名以 `#` 开头，行协议中可以省略
+pub fn normalize_room(name: &str) -> Result<String, ChatError> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    if name.is_empty()
+        || name.chars().count() > config::get().limits.max_room_name_len
+        || !name.chars().all(mention::is_name_char)
+    {
+        return Err(ChatError::InvalidRoom(name.to_string()));
+    }
+    Ok(format!("#{name}"))
+}
+
+pub fn validate_username(username: &str) -> Result<(), ChatError> {
+    let max_len = config::get().limits.max_username_len;
+    if username.is_empty() || username.chars().count() > max_len {
+        return Err(ChatError::InvalidUsername(format!(
+            "username must be 1 to {max_len} characters"
+        )));
+    }
+    if !username.chars().all(mention::is_name_char) {
+        return Err(ChatError::InvalidUsername(
+            "username can only contain letters, digits, '_' and '-'".to_string(),
+        ));
+    }
+    Ok(())
+}
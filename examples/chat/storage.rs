@@ -0,0 +1,26 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+use crate::error::ChatError;
+
+/// 文件不存在时返回默认值
+pub async fn load_json<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 先写临时文件再 rename，避免写到一半时崩溃留下损坏的文件
+pub async fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), ChatError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let content = serde_json::to_string_pretty(value)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
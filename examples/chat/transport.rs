@@ -0,0 +1,65 @@
+/*
+传输方式
+    - TCP：行协议和 IRC 前端
+    - Unix domain socket：行协议，方便本地工具和测试连接
+    - 本地控制台：服务器自己的 stdin/stdout 作为一个 peer
+    - 前端只依赖双向字节流，peer 用生成的 PeerId 区分，和具体的传输方式无关
+*/
+
+use std::{io, path::Path};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite, Join, Stdin, Stdout},
+    net::{TcpListener, UnixListener, UnixStream},
+};
+
+/// 前端可以处理的任意双向字节流
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
+pub trait Listener {
+    type Stream: Connection;
+
+    /// 返回连接和用于日志的对端描述
+    async fn accept(&self) -> io::Result<(Self::Stream, String)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> io::Result<(Self::Stream, String)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream, addr.to_string()))
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(Self::Stream, String)> {
+        let (stream, _) = UnixListener::accept(self).await?;
+        Ok((stream, "unix socket".to_string()))
+    }
+}
+
+/// 删除上次没有清理的 socket 文件再监听，另一个服务还在使用时报错
+pub async fn bind_unix(path: impl AsRef<Path>) -> anyhow::Result<UnixListener> {
+    let path = path.as_ref();
+    if UnixStream::connect(path).await.is_ok() {
+        anyhow::bail!("{} is in use by another server", path.display());
+    }
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+pub fn console() -> Join<Stdin, Stdout> {
+    tokio::io::join(tokio::io::stdin(), tokio::io::stdout())
+}
@@ -0,0 +1,377 @@
+/*
+外发 webhook
+    - 在数据目录的 webhooks.json 中配置，每个 webhook 有 URL、可选的签名密钥和关心的事件
+    - 事件：某个房间的消息、@ 到某个用户、加入和离开房间，不写房间或用户时匹配所有
+    - 每个 webhook 有独立的有界队列和投递 task，队列满时丢弃事件，不会阻塞广播
+    - 失败时按指数退避重试，4xx（429 除外）不重试
+    - 设置了密钥时带上 `X-Chat-Signature: sha256=<hex>`，对请求体做 HMAC-SHA256
+*/
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{path::Path, sync::Arc, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+
+use crate::{history::ChatRecord, storage::load_json};
+
+const QUEUE_SIZE: usize = 256;
+const RETRY: Retry = Retry {
+    attempts: 5,
+    initial_backoff: Duration::from_millis(500),
+    max_backoff: Duration::from_secs(30),
+};
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+
+/// 投递失败时最多尝试的次数和指数退避的范围
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct WebhookConfig {
+    url: String,
+    secret: Option<String>,
+    events: Vec<EventFilter>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventFilter {
+    Message { room: Option<String> },
+    Mention { user: Option<String> },
+    Join { room: Option<String> },
+    Part { room: Option<String> },
+}
+
+/// 发给 webhook 的事件
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Message(ChatRecord),
+    Mention { user: String, message: ChatRecord },
+    Join { room: String, username: String },
+    Part { room: String, username: String },
+}
+
+/// 请求体，重试时 ID 不变，接收方可以用来去重
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    delivery: &'a str,
+    sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Debug)]
+struct Hook {
+    url: String,
+    events: Vec<EventFilter>,
+    sender: mpsc::Sender<Arc<Event>>,
+}
+
+#[derive(Debug)]
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+}
+
+impl Webhooks {
+    /// 读取配置并为每个 webhook 启动投递 task
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let configs: Vec<WebhookConfig> = load_json(path.as_ref()).await?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let hooks = configs
+            .into_iter()
+            .map(|config| {
+                let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+                info!("Forward chat events to {}", config.url);
+                let hook = Hook {
+                    url: config.url.clone(),
+                    events: config.events.clone(),
+                    sender: tx,
+                };
+                tokio::spawn(deliver(client.clone(), config, rx, RETRY));
+                hook
+            })
+            .collect();
+        Ok(Self { hooks })
+    }
+
+    /// 只放入队列，不等待投递
+    pub fn emit(&self, event: Event) {
+        let event = Arc::new(event);
+        for hook in &self.hooks {
+            if !hook.events.iter().any(|filter| filter.matches(&event)) {
+                continue;
+            }
+            match hook.sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Webhook queue of {} is full, drop the event", hook.url)
+                }
+                Err(TrySendError::Closed(_)) => warn!("Webhook task of {} has stopped", hook.url),
+            }
+        }
+    }
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        let matches = |expected: &Option<String>, actual: &str| {
+            expected
+                .as_deref()
+                .is_none_or(|expected| expected == actual)
+        };
+        match (self, event) {
+            (Self::Message { room }, Event::Message(record)) => matches(room, &record.room),
+            (Self::Mention { user: expected }, Event::Mention { user, .. }) => {
+                matches(expected, user)
+            }
+            (Self::Join { room: expected }, Event::Join { room, .. }) => matches(expected, room),
+            (Self::Part { room: expected }, Event::Part { room, .. }) => matches(expected, room),
+            _ => false,
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    config: WebhookConfig,
+    mut rx: mpsc::Receiver<Arc<Event>>,
+    retry: Retry,
+) {
+    while let Some(event) = rx.recv().await {
+        let delivery = nanoid::nanoid!();
+        let payload = Payload {
+            delivery: &delivery,
+            sent_at: Utc::now(),
+            event: &event,
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to encode webhook event: {e}");
+                continue;
+            }
+        };
+
+        let mut backoff = retry.initial_backoff;
+        for attempt in 1..=retry.attempts {
+            let mut request = client
+                .post(&config.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &config.secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+            let retryable = match request.send().await {
+                Ok(response) if response.status().is_success() => break,
+                Ok(response) => {
+                    let status = response.status();
+                    warn!("Webhook {} returned {status} for {delivery}", config.url);
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => {
+                    warn!("Failed to deliver {delivery} to {}: {e}", config.url);
+                    true
+                }
+            };
+            if !retryable || attempt == retry.attempts {
+                warn!("Give up delivering {delivery} to {}", config.url);
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(retry.max_backoff);
+        }
+    }
+}
+
+/// `sha256=<hex>`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={digest}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use std::{
+        collections::VecDeque,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    const FAST_RETRY: Retry = Retry {
+        attempts: 4,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+    };
+
+    /// 按顺序返回 `statuses` 中的状态码，用完之后返回 200，记录收到的请求
+    #[derive(Debug, Default)]
+    struct Receiver {
+        statuses: Mutex<VecDeque<StatusCode>>,
+        requests: Mutex<Vec<(HeaderMap, Bytes)>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Arc<Receiver>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        receiver.requests.lock().unwrap().push((headers, body));
+        let status = receiver.statuses.lock().unwrap().pop_front();
+        status.unwrap_or(StatusCode::OK)
+    }
+
+    /// 启动接收方，投递一个事件，等投递 task 结束后返回收到的请求
+    async fn deliver_one(statuses: &[StatusCode], secret: Option<&str>) -> Vec<(HeaderMap, Bytes)> {
+        let receiver = Arc::new(Receiver {
+            statuses: Mutex::new(statuses.iter().copied().collect()),
+            ..Receiver::default()
+        });
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = WebhookConfig {
+            url: format!("http://{addr}/hook"),
+            secret: secret.map(str::to_string),
+            events: Vec::new(),
+        };
+        let (tx, rx) = mpsc::channel(1);
+        let task = tokio::spawn(deliver(reqwest::Client::new(), config, rx, FAST_RETRY));
+        tx.send(Arc::new(join_event())).await.unwrap();
+        drop(tx);
+        task.await.unwrap();
+        let requests = std::mem::take(&mut *receiver.requests.lock().unwrap());
+        requests
+    }
+
+    fn join_event() -> Event {
+        Event::Join {
+            room: "#general".to_string(),
+            username: "alice".to_string(),
+        }
+    }
+
+    #[test]
+    fn signature_is_hmac_sha256_of_the_body() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_signed_when_a_secret_is_set() {
+        let requests = deliver_one(&[], Some("s3cret")).await;
+        assert_eq!(requests.len(), 1);
+        let (headers, body) = &requests[0];
+        assert_eq!(headers[SIGNATURE_HEADER], sign("s3cret", body));
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["event"], "join");
+        assert_eq!(payload["room"], "#general");
+
+        let requests = deliver_one(&[], None).await;
+        assert!(!requests[0].0.contains_key(SIGNATURE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_rate_limits_are_retried() {
+        let statuses = [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ];
+        let requests = deliver_one(&statuses, None).await;
+        assert_eq!(requests.len(), 4);
+        //重试时请求体不变，接收方可以按 delivery 去重
+        assert!(requests.iter().all(|(_, body)| body == &requests[0].1));
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_last_attempt() {
+        let statuses = [StatusCode::BAD_GATEWAY; 10];
+        let requests = deliver_one(&statuses, None).await;
+        assert_eq!(requests.len(), FAST_RETRY.attempts as usize);
+    }
+
+    #[tokio::test]
+    async fn other_client_errors_are_not_retried() {
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+        ] {
+            let requests = deliver_one(&[status], None).await;
+            assert_eq!(requests.len(), 1, "{status} should not be retried");
+        }
+    }
+
+    #[test]
+    fn emit_drops_events_when_the_queue_is_full() {
+        let (sender, mut rx) = mpsc::channel(2);
+        let webhooks = Webhooks {
+            hooks: vec![Hook {
+                url: "http://127.0.0.1:9/hook".to_string(),
+                events: vec![EventFilter::Join { room: None }],
+                sender,
+            }],
+        };
+        //没有 task 在读队列，emit 也要立刻返回
+        let start = Instant::now();
+        for _ in 0..100 {
+            webhooks.emit(join_event());
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let mut queued = 0;
+        while rx.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, 2);
+    }
+
+    #[test]
+    fn emit_only_queues_matching_events() {
+        let (sender, mut rx) = mpsc::channel(8);
+        let webhooks = Webhooks {
+            hooks: vec![Hook {
+                url: "http://127.0.0.1:9/hook".to_string(),
+                events: vec![EventFilter::Join {
+                    room: Some("#dev".to_string()),
+                }],
+                sender,
+            }],
+        };
+        webhooks.emit(join_event());
+        assert!(rx.try_recv().is_err());
+    }
+}
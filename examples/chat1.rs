@@ -1,4 +1,10 @@
+//和 chat 共用配置文件，这里只用到监听地址、队列大小和日志级别
+#[path = "common/chat_config.rs"]
+mod config;
+
 use anyhow::Result;
+use clap::Parser;
+use config::{Config, ConfigArgs};
 use core::fmt;
 use dashmap::DashMap;
 use futures::{stream::SplitStream, SinkExt, StreamExt};
@@ -7,10 +13,9 @@ use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::{self, Sender};
 use tokio_util::codec::{Framed, LinesCodec};
-use tracing::{info, level_filters::LevelFilter, warn};
+use tracing::{info, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
-const MAX_MESSAGES: usize = 128;
 type Reveiver = SplitStream<Framed<TcpStream, LinesCodec>>;
 
 #[derive(Debug, Default, Clone)]
@@ -25,12 +30,26 @@ enum Message {
     Chat { username: String, content: String },
 }
 
+/// A minimal TCP chat server
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config)?;
+    if cli.config.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let config = config.install();
+    let layer = Layer::new().pretty().with_filter(config.level()?);
     tracing_subscriber::registry().with(layer).init();
 
-    let addr = "0.0.0.0:8082";
+    let addr = config.listen.native;
     let listener = TcpListener::bind(addr).await?;
     info!("listening on {addr}");
     let state = Arc::new(AppState::default());
@@ -91,7 +110,7 @@ impl AppState {
     }
 
     fn add_user(&self, addr: SocketAddr, socket: Framed<TcpStream, LinesCodec>) -> Reveiver {
-        let (tx, mut rx) = mpsc::channel(MAX_MESSAGES);
+        let (tx, mut rx) = mpsc::channel(config::get().delivery.queue_size);
         self.inner.insert(addr, tx);
 
         let (mut sender, receiver) = socket.split();
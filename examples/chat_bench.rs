@@ -0,0 +1,386 @@
+/*
+TCP Chat Server 的压测工具
+    - 同时打开大量模拟 client，登录后加入同一个新建的房间
+    - 所有 client 连上之后等服务器发完加入房间的通知，然后开始计时，按总速率均匀地发送消息，消息内容带有发送时间
+    - 每个 client 收到广播时计算端到端延迟，最后汇总延迟分位数、吞吐量和丢失的消息
+    - 报告可以用 --save 保存为 JSON，再用 --baseline 和之前的报告对比
+
+    cargo run --release --example chat
+    cargo run --release --example chat_bench -- --clients 2000 --rate 200 --save bench.json
+
+client 很多时需要先调大文件描述符上限，比如 `ulimit -n 65536`
+*/
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpStream,
+    sync::{Barrier, Semaphore},
+    time::{interval_at, timeout, timeout_at, Instant, MissedTickBehavior},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// 几千个 client 加入房间时会产生几百万条通知，等它们发完
+const SYNC_TIMEOUT: Duration = Duration::from_secs(300);
+/// 停止发送后继续接收的时间，等待还在路上的消息
+const DRAIN: Duration = Duration::from_secs(5);
+const MARKER: &str = ": bench ";
+
+/// 名称、取值、是否越大越好
+type Metric = (&'static str, fn(&Report) -> f64, bool);
+
+const METRICS: [Metric; 12] = [
+    ("connected", |r| r.connected as f64, true),
+    ("sent", |r| r.sent as f64, true),
+    ("delivered", |r| r.delivered as f64, true),
+    ("lost %", |r| r.lost_percent(), false),
+    ("sent/s", |r| r.sent_per_sec, true),
+    ("delivered/s", |r| r.delivered_per_sec, true),
+    ("latency mean ms", |r| r.latency_ms.mean, false),
+    ("latency p50 ms", |r| r.latency_ms.p50, false),
+    ("latency p90 ms", |r| r.latency_ms.p90, false),
+    ("latency p99 ms", |r| r.latency_ms.p99, false),
+    ("latency p99.9 ms", |r| r.latency_ms.p999, false),
+    ("latency max ms", |r| r.latency_ms.max, false),
+];
+
+#[derive(Debug, Parser)]
+#[command(about = "Load test the chat server and report delivery latency")]
+struct Args {
+    /// Address of the chat server (line protocol)
+    #[arg(short, long, default_value = "127.0.0.1:8082")]
+    addr: String,
+    /// Number of simulated clients
+    #[arg(short, long, default_value_t = 1000)]
+    clients: usize,
+    /// Messages per second sent across all clients
+    #[arg(short, long, default_value_t = 100.0)]
+    rate: f64,
+    /// Seconds to keep sending
+    #[arg(short, long, default_value_t = 30)]
+    duration: u64,
+    /// Connections being set up at the same time while ramping up
+    #[arg(long, default_value_t = 64)]
+    connect_concurrency: usize,
+    /// Name of this run in the report, e.g. a commit hash
+    #[arg(short, long)]
+    label: Option<String>,
+    /// Write the report as JSON
+    #[arg(long)]
+    save: Option<PathBuf>,
+    /// Compare with a report saved by an earlier run
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    label: Option<String>,
+    clients: usize,
+    connected: usize,
+    rate: f64,
+    duration_secs: u64,
+    sent: u64,
+    delivered: u64,
+    /// 每条消息应该送到房间里的每个 client，包括发送者自己
+    expected: u64,
+    sent_per_sec: f64,
+    delivered_per_sec: f64,
+    latency_ms: Latency,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Latency {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    p999: f64,
+    max: f64,
+}
+
+#[derive(Debug, Default)]
+struct ClientStats {
+    sent: u64,
+    /// 微秒
+    latencies: Vec<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    anyhow::ensure!(args.clients > 0, "--clients must be positive");
+    anyhow::ensure!(args.rate > 0.0, "--rate must be positive");
+
+    let baseline: Option<Report> = match &args.baseline {
+        Some(path) => {
+            let data = std::fs::read(path).with_context(|| format!("reading {path:?}"))?;
+            Some(serde_json::from_slice(&data)?)
+        }
+        None => None,
+    };
+
+    let run = nanoid::nanoid!(6);
+    let room = format!("#bench-{run}");
+    let epoch = Instant::now();
+    // 主 task 也参与等待，所有 client 登录完成（或者失败）后一起同步，同步完再一起开始
+    let barrier = Arc::new(Barrier::new(args.clients + 1));
+    let connect = Arc::new(Semaphore::new(args.connect_concurrency.max(1)));
+    let period = Duration::from_secs_f64(args.clients as f64 / args.rate);
+    let duration = Duration::from_secs(args.duration);
+
+    println!(
+        "connecting {} clients to {} in {room}",
+        args.clients, args.addr
+    );
+    let tasks: Vec<_> = (0..args.clients)
+        .map(|i| {
+            let addr = args.addr.clone();
+            let username = format!("bench-{run}-{i}");
+            let room = room.clone();
+            let barrier = barrier.clone();
+            let connect = connect.clone();
+            // 把各个 client 的第一次发送均匀地错开
+            let offset = period.mul_f64(i as f64 / args.clients as f64);
+            tokio::spawn(async move {
+                let session = {
+                    let _permit = connect.acquire().await?;
+                    timeout(LOGIN_TIMEOUT, login(&addr, &username, &room)).await
+                };
+                barrier.wait().await;
+                let session = match session {
+                    Ok(Ok(framed)) => timeout(SYNC_TIMEOUT, sync(framed, &room))
+                        .await
+                        .context("sync timed out")
+                        .and_then(|framed| framed),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(anyhow::anyhow!("login timed out")),
+                };
+                barrier.wait().await;
+                let framed = session?;
+                let start = Instant::now();
+                run_client(
+                    framed,
+                    &room,
+                    epoch,
+                    start + offset,
+                    period,
+                    start + duration,
+                )
+                .await
+            })
+        })
+        .collect();
+
+    barrier.wait().await;
+    println!("waiting for join notices to drain");
+    barrier.wait().await;
+    println!("sending for {} seconds", args.duration);
+
+    let mut stats = ClientStats::default();
+    let mut connected = 0;
+    for task in tasks {
+        match task.await? {
+            Ok(client) => {
+                connected += 1;
+                stats.sent += client.sent;
+                stats.latencies.extend(client.latencies);
+            }
+            Err(e) => eprintln!("client failed: {e:#}"),
+        }
+    }
+
+    let report = Report::new(&args, connected, stats);
+    report.print(baseline.as_ref());
+    if let Some(path) = &args.save {
+        std::fs::write(path, serde_json::to_vec_pretty(&report)?)
+            .with_context(|| format!("writing {path:?}"))?;
+        println!("report saved to {path:?}");
+    }
+    Ok(())
+}
+
+/// 登录并加入压测房间，收到房间的用户列表后返回
+async fn login(addr: &str, username: &str, room: &str) -> Result<Framed<TcpStream, LinesCodec>> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let mut framed = Framed::new(stream, LinesCodec::new());
+    let names = format!("[users in {room}:");
+    while let Some(line) = framed.next().await.transpose()? {
+        if line == "please enter your username:" {
+            framed.send(username).await?;
+        } else if line.starts_with("[welcome ") {
+            framed.send(format!("/join {room}")).await?;
+        } else if line.starts_with(&names) {
+            return Ok(framed);
+        } else if line == "please enter your password:" {
+            anyhow::bail!("{username} is a registered user");
+        }
+    }
+    anyhow::bail!("server closed the connection during login")
+}
+
+/// 给自己发一条回复，收到时服务器已经把之前排队的消息都发完了
+async fn sync(
+    mut framed: Framed<TcpStream, LinesCodec>,
+    room: &str,
+) -> Result<Framed<TcpStream, LinesCodec>> {
+    framed.send("/topic").await?;
+    let reply = format!("[{room} has no topic]");
+    while let Some(line) = framed.next().await.transpose()? {
+        if line == reply {
+            return Ok(framed);
+        }
+    }
+    anyhow::bail!("server closed the connection while syncing")
+}
+
+async fn run_client(
+    framed: Framed<TcpStream, LinesCodec>,
+    room: &str,
+    epoch: Instant,
+    first: Instant,
+    period: Duration,
+    deadline: Instant,
+) -> Result<ClientStats> {
+    let (mut sink, mut stream) = framed.split();
+    let prefix = format!("[{room}] #");
+
+    let send = async {
+        let mut sent = 0;
+        let mut ticks = interval_at(first, period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let Ok(_) = timeout_at(deadline, ticks.tick()).await else {
+                break;
+            };
+            let micros = Instant::now().duration_since(epoch).as_micros();
+            sink.send(format!("bench {micros}")).await?;
+            sent += 1;
+        }
+        anyhow::Ok((sent, sink))
+    };
+
+    let receive = async {
+        let mut latencies = Vec::new();
+        while let Ok(Some(line)) = timeout_at(deadline + DRAIN, stream.next()).await {
+            let line = line?;
+            let Some(sent_at) = line
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.split_once(MARKER))
+                .and_then(|(_, micros)| micros.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let now = Instant::now().duration_since(epoch).as_micros() as u64;
+            latencies.push(now.saturating_sub(sent_at));
+        }
+        anyhow::Ok(latencies)
+    };
+
+    let (sent, latencies) = tokio::join!(send, receive);
+    let (sent, mut sink) = sent?;
+    let latencies = latencies?;
+    let _ = sink.close().await;
+    Ok(ClientStats { sent, latencies })
+}
+
+impl Report {
+    fn new(args: &Args, connected: usize, mut stats: ClientStats) -> Self {
+        let seconds = args.duration.max(1) as f64;
+        let delivered = stats.latencies.len() as u64;
+        Self {
+            label: args.label.clone(),
+            clients: args.clients,
+            connected,
+            rate: args.rate,
+            duration_secs: args.duration,
+            sent: stats.sent,
+            delivered,
+            expected: stats.sent * connected as u64,
+            sent_per_sec: stats.sent as f64 / seconds,
+            delivered_per_sec: delivered as f64 / seconds,
+            latency_ms: Latency::from_micros(&mut stats.latencies),
+        }
+    }
+
+    /// 有 baseline 时并排显示，并标出变化超过 1% 的指标
+    fn print(&self, baseline: Option<&Report>) {
+        let label = |report: &Report| report.label.clone().unwrap_or_else(|| "-".to_string());
+        println!();
+        print_row("", &label(self), baseline.map(label).as_deref(), "");
+        for (name, metric, higher_is_better) in METRICS {
+            let value = metric(self);
+            let Some(before) = baseline.map(metric) else {
+                print_row(name, &format_value(value), None, "");
+                continue;
+            };
+            let change = if before == 0.0 {
+                String::new()
+            } else {
+                let delta = (value - before) / before * 100.0;
+                let verdict = match delta.abs() < 1.0 {
+                    true => "",
+                    false if (delta > 0.0) == higher_is_better => " better",
+                    false => " worse",
+                };
+                format!("{delta:+.1}%{verdict}")
+            };
+            print_row(
+                name,
+                &format_value(value),
+                Some(&format_value(before)),
+                &change,
+            );
+        }
+    }
+
+    fn lost_percent(&self) -> f64 {
+        if self.expected == 0 {
+            return 0.0;
+        }
+        self.expected.saturating_sub(self.delivered) as f64 / self.expected as f64 * 100.0
+    }
+}
+
+fn print_row(name: &str, value: &str, baseline: Option<&str>, change: &str) {
+    match baseline {
+        Some(baseline) => println!("{name:<18}{value:>14}{baseline:>14}  {change}"),
+        None => println!("{name:<18}{value:>14}"),
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{value:.0}")
+    } else {
+        format!("{value:.2}")
+    }
+}
+
+impl Latency {
+    fn from_micros(samples: &mut [u64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * (samples.len() - 1) as f64).round() as usize;
+            ms(samples[rank])
+        };
+        let total: u64 = samples.iter().sum();
+        Self {
+            mean: ms(total) / samples.len() as f64,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            p999: percentile(99.9),
+            max: ms(samples[samples.len() - 1]),
+        }
+    }
+}
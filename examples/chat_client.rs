@@ -0,0 +1,495 @@
+/*
+TCP Chat Server 的终端客户端
+    - 连接界面：输入服务器地址、用户名，以及注册用户的密码
+    - 聊天界面：可滚动的消息列表 + 在线用户侧边栏，@ 到自己的消息高亮显示
+    - 断线后按指数退避自动重连，重连成功后重新登录；登录被拒绝时回到连接界面
+*/
+
+#[path = "common/chat_ui.rs"]
+mod chat_ui;
+
+use anyhow::Result;
+use chat_ui::ChatView;
+use crossterm::{
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{SinkExt, StreamExt};
+use std::{collections::BTreeSet, io, time::Duration};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, ListItem, Paragraph},
+    Frame, Terminal,
+};
+use unicode_width::UnicodeWidthStr;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:8082";
+const MAX_EVENTS: usize = 128;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+enum Screen {
+    Connect,
+    Chat,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Addr,
+    Username,
+    Password,
+}
+
+#[derive(Debug)]
+enum NetEvent {
+    Connected,
+    Line(String),
+    Disconnected(String),
+    Rejected(String),
+}
+
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+enum SessionEnd {
+    Closed,
+    Rejected(String),
+    Quit,
+}
+
+struct ClientApp {
+    screen: Screen,
+    focus: Field,
+    addr: String,
+    username: String,
+    password: String,
+    input: String,
+    messages: Vec<String>,
+    /// 最近一次收到用户列表的房间，侧边栏只显示这个房间的用户
+    room: String,
+    users: BTreeSet<String>,
+    scroll: usize,
+    status: String,
+    outgoing: Option<mpsc::Sender<String>>,
+}
+
+impl ClientApp {
+    fn new() -> Self {
+        ClientApp {
+            screen: Screen::Connect,
+            focus: Field::Addr,
+            addr: DEFAULT_ADDR.to_string(),
+            username: String::new(),
+            password: String::new(),
+            input: String::new(),
+            messages: Vec::new(),
+            room: String::new(),
+            users: BTreeSet::new(),
+            scroll: 0,
+            status: String::new(),
+            outgoing: None,
+        }
+    }
+
+    async fn run<B: Backend>(mut self, terminal: &mut Terminal<B>) -> Result<()> {
+        let (events_tx, mut events_rx) = mpsc::channel(MAX_EVENTS);
+        loop {
+            while let Ok(event) = events_rx.try_recv() {
+                self.on_net_event(event);
+            }
+
+            terminal.draw(|f| self.ui(f))?;
+
+            if !event::poll(Duration::from_millis(20))? {
+                continue;
+            }
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.code == KeyCode::Esc {
+                return Ok(());
+            }
+            match self.screen {
+                Screen::Connect => self.on_connect_key(key.code, &events_tx),
+                Screen::Chat => self.on_chat_key(key.code),
+            }
+        }
+    }
+
+    fn on_connect_key(&mut self, code: KeyCode, events: &mpsc::Sender<NetEvent>) {
+        let field = match self.focus {
+            Field::Addr => &mut self.addr,
+            Field::Username => &mut self.username,
+            Field::Password => &mut self.password,
+        };
+        match code {
+            KeyCode::Tab | KeyCode::Down => {
+                self.focus = match self.focus {
+                    Field::Addr => Field::Username,
+                    Field::Username => Field::Password,
+                    Field::Password => Field::Addr,
+                };
+            }
+            KeyCode::Up => {
+                self.focus = match self.focus {
+                    Field::Addr => Field::Password,
+                    Field::Username => Field::Addr,
+                    Field::Password => Field::Username,
+                };
+            }
+            KeyCode::Char(c) => field.push(c),
+            KeyCode::Backspace => {
+                field.pop();
+            }
+            KeyCode::Enter => {
+                if self.addr.trim().is_empty() || self.username.trim().is_empty() {
+                    self.status = "address and username are required".to_string();
+                    return;
+                }
+                let (tx, rx) = mpsc::channel(MAX_EVENTS);
+                let addr = self.addr.trim().to_string();
+                let credentials = Credentials {
+                    username: self.username.trim().to_string(),
+                    password: self.password.clone(),
+                };
+                tokio::spawn(connection(addr, credentials, events.clone(), rx));
+                self.outgoing = Some(tx);
+                self.status = format!("connecting to {}...", self.addr.trim());
+                self.screen = Screen::Chat;
+            }
+            _ => {}
+        }
+    }
+
+    fn on_chat_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Enter => {
+                if self.input.is_empty() {
+                    return;
+                }
+                let Some(outgoing) = &self.outgoing else {
+                    return;
+                };
+                //断线期间消息留在 channel 中，重连后再发送；缓冲满了时保留输入，不阻塞界面
+                match outgoing.try_send(self.input.clone()) {
+                    Ok(()) => self.input.clear(),
+                    Err(TrySendError::Full(_)) => {
+                        self.status =
+                            "too many unsent messages, wait for the connection".to_string();
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        self.status = "connection task has stopped".to_string();
+                    }
+                }
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => self.scroll_up(1),
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll_up(10),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::End => self.scroll = 0,
+            _ => {}
+        }
+    }
+
+    fn on_net_event(&mut self, event: NetEvent) {
+        match event {
+            NetEvent::Connected => {
+                self.status = format!("connected to {}", self.addr.trim());
+                self.room.clear();
+                self.users.clear();
+            }
+            NetEvent::Line(line) => {
+                self.track_users(&line);
+                self.push_message(line);
+            }
+            NetEvent::Disconnected(reason) => {
+                self.status = format!("disconnected ({reason}), reconnecting...");
+                self.users.clear();
+            }
+            NetEvent::Rejected(reason) => {
+                self.status = format!("login rejected: {reason}");
+                self.users.clear();
+                self.outgoing = None;
+                self.screen = Screen::Connect;
+            }
+        }
+    }
+
+    /// 根据服务器发来的行维护当前房间的用户列表，格式见 chat/message.rs 中 `Message` 的 Display 实现
+    fn track_users(&mut self, line: &str) {
+        let Some(event) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) else {
+            return;
+        };
+        if let Some((room, list)) = event
+            .strip_prefix("users in ")
+            .and_then(|s| s.split_once(": "))
+        {
+            self.room = room.to_string();
+            self.users = list
+                .split(", ")
+                .filter(|name| !name.is_empty())
+                //房间管理员带有 `@` 前缀
+                .map(|name| name.trim_start_matches('@').to_string())
+                .collect();
+        } else if let Some(name) = event.strip_suffix(" has left the chat :(") {
+            self.users.remove(name);
+        } else if let Some((name, room)) = event.split_once(" has joined ") {
+            if room == self.room {
+                self.users.insert(name.to_string());
+            }
+        } else if let Some((name, room)) = event.split_once(" has left ") {
+            if room == self.room {
+                self.users.remove(name);
+            }
+        }
+    }
+
+    fn push_message(&mut self, message: String) {
+        self.messages.push(message);
+        //用户正在向上翻看历史时保持视图不动
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        self.scroll = (self.scroll + n).min(self.messages.len().saturating_sub(1));
+    }
+
+    fn ui<B: Backend>(&self, frame: &mut Frame<B>) {
+        match self.screen {
+            Screen::Connect => self.connect_ui(frame),
+            Screen::Chat => self.chat_ui(frame),
+        }
+    }
+
+    fn connect_ui<B: Backend>(&self, frame: &mut Frame<B>) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(2)
+            .constraints(
+                [
+                    Constraint::Length(1),
+                    Constraint::Length(3),
+                    Constraint::Length(3),
+                    Constraint::Length(3),
+                    Constraint::Length(1),
+                    Constraint::Min(0),
+                ]
+                .as_ref(),
+            )
+            .split(frame.size());
+
+        let help_message = Paragraph::new(Spans::from(vec![
+            Span::raw("Press "),
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" to switch field, "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" to connect, "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" to quit"),
+        ]));
+        frame.render_widget(help_message, chunks[0]);
+
+        let masked = "*".repeat(self.password.chars().count());
+        let fields = [
+            (Field::Addr, "Server", &self.addr, chunks[1]),
+            (Field::Username, "Username", &self.username, chunks[2]),
+            (
+                Field::Password,
+                "Password (registered users only)",
+                &masked,
+                chunks[3],
+            ),
+        ];
+        for (field, title, value, area) in fields {
+            let style = if field == self.focus {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            };
+            let input = Paragraph::new(value.as_str())
+                .style(style)
+                .block(Block::default().borders(Borders::ALL).title(title));
+            frame.render_widget(input, area);
+            if field == self.focus {
+                frame.set_cursor(area.x + value.width() as u16 + 1, area.y + 1);
+            }
+        }
+
+        let status = Paragraph::new(self.status.as_str()).style(Style::default().fg(Color::Red));
+        frame.render_widget(status, chunks[4]);
+    }
+
+    fn chat_ui<B: Backend>(&self, frame: &mut Frame<B>) {
+        let messages = self
+            .messages
+            .iter()
+            .map(|m| {
+                let item = ListItem::new(Spans::from(Span::raw(m.as_str())));
+                if m.starts_with("[mention]") {
+                    item.style(Style::default().fg(Color::Yellow))
+                } else {
+                    item
+                }
+            })
+            .collect();
+        let users = self
+            .users
+            .iter()
+            .map(|u| ListItem::new(Spans::from(Span::raw(u.as_str()))))
+            .collect();
+        let title = format!("Messages {} - {}", self.room, self.status);
+        let view = ChatView {
+            hints: &[
+                ("Enter", "send the message"),
+                ("PgUp/PgDn", "scroll"),
+                ("Esc", "quit"),
+            ],
+            input: &self.input,
+            title: &title,
+            messages,
+            scroll: self.scroll,
+            users: Some(users),
+        };
+        chat_ui::draw(frame, view);
+    }
+}
+
+/// 维持与服务器的连接，断线后按指数退避重连，登录被拒绝时不再重试
+async fn connection(
+    addr: String,
+    credentials: Credentials,
+    events: mpsc::Sender<NetEvent>,
+    mut outgoing: mpsc::Receiver<String>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let reason = match session(&addr, &credentials, &events, &mut outgoing).await {
+            Ok(SessionEnd::Closed) => {
+                backoff = MIN_BACKOFF;
+                "server closed the connection".to_string()
+            }
+            Ok(SessionEnd::Rejected(reason)) => {
+                let _ = events.send(NetEvent::Rejected(reason)).await;
+                return;
+            }
+            //UI 已经退出
+            Ok(SessionEnd::Quit) => return,
+            Err(e) => e.to_string(),
+        };
+        if events.send(NetEvent::Disconnected(reason)).await.is_err() {
+            return;
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// 一次连接的生命周期
+async fn session(
+    addr: &str,
+    credentials: &Credentials,
+    events: &mpsc::Sender<NetEvent>,
+    outgoing: &mut mpsc::Receiver<String>,
+) -> Result<SessionEnd> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut framed = Framed::new(stream, LinesCodec::new());
+
+    //登录：服务器可能要求输入密码，收到欢迎消息表示登录成功，再次要求输入用户名表示被拒绝
+    let mut username_sent = false;
+    let mut last_notice = String::new();
+    loop {
+        let Some(line) = framed.next().await else {
+            return Ok(SessionEnd::Closed);
+        };
+        let line = line?;
+        match line.as_str() {
+            "please enter your username:" if username_sent => {
+                return Ok(SessionEnd::Rejected(last_notice));
+            }
+            "please enter your username:" => {
+                framed.send(&credentials.username).await?;
+                username_sent = true;
+            }
+            "please enter your password:" if credentials.password.is_empty() => {
+                return Ok(SessionEnd::Rejected("password required".to_string()));
+            }
+            "please enter your password:" => framed.send(&credentials.password).await?,
+            _ if line.starts_with("[welcome ") => {
+                if events.send(NetEvent::Connected).await.is_err()
+                    || events.send(NetEvent::Line(line)).await.is_err()
+                {
+                    return Ok(SessionEnd::Quit);
+                }
+                break;
+            }
+            _ => last_notice = line,
+        }
+    }
+
+    loop {
+        tokio::select! {
+            line = framed.next() => {
+                let Some(line) = line else {
+                    return Ok(SessionEnd::Closed);
+                };
+                if events.send(NetEvent::Line(line?)).await.is_err() {
+                    return Ok(SessionEnd::Quit);
+                }
+            }
+            line = outgoing.recv() => {
+                let Some(line) = line else {
+                    return Ok(SessionEnd::Quit);
+                };
+                framed.send(line).await?;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // setup terminal
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    // create app and run it
+    let app = ClientApp::new();
+    let res = app.run(&mut terminal).await;
+
+    // restore terminal
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+    )?;
+    terminal.show_cursor()?;
+
+    if let Err(err) = res {
+        println!("{err:?}")
+    }
+
+    Ok(())
+}
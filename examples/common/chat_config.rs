@@ -0,0 +1,610 @@
+/*
+聊天服务的配置
+    - 依次合并：默认值、TOML 配置文件、环境变量、命令行参数，后面的覆盖前面的
+    - 配置文件用 --config 指定，没有指定时读取 $CHAT_CONFIG，都没有时读取当前目录下的 chat.toml（如果存在）
+    - 环境变量 CHAT_<SECTION>_<KEY> 覆盖对应的配置，比如 CHAT_LIMITS_MAILBOX_QUOTA=200
+    - 命令行 --set section.key=value 可以覆盖任意配置，常用的配置有单独的参数
+    - 启动时检查所有配置，有错误立即退出；--print-config 打印最终生效的配置，不显示密码
+    - 内容过滤的屏蔽词、正则和每个房间的策略写在 [filters] 中，比如：
+
+        [filters]
+        blocked_words = ["spam"]
+        blocked_patterns = ['(?i)buy\s+now']
+        default = { pipeline = ["sanitize", "blocklist"], on_blocked = "mask" }
+        rooms."#dev" = { pipeline = ["sanitize", "secrets", "blocklist"] }
+
+    - 聊天记录的保留策略写在 [retention] 中，没有配置时永久保留，比如：
+
+        [retention]
+        default = { max_age_days = 90 }
+        rooms."#random" = { max_count = 1000, max_bytes = 1048576 }
+*/
+
+use anyhow::{anyhow, ensure, Context};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tracing::level_filters::LevelFilter;
+
+const CONFIG_ENV: &str = "CHAT_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "chat.toml";
+const ENV_PREFIX: &str = "CHAT_";
+/// 旧版本用来设置 operator 密码的环境变量，等同于 CHAT_AUTH_OPER_PASSWORD
+const LEGACY_OPER_PASSWORD_ENV: &str = "CHAT_OPER_PASSWORD";
+const SECTIONS: [&str; 9] = [
+    "listen",
+    "limits",
+    "delivery",
+    "rooms",
+    "auth",
+    "storage",
+    "log",
+    "filters",
+    "retention",
+];
+
+/// 启动时加载一次，之后只读
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Listen,
+    pub limits: Limits,
+    pub delivery: Delivery,
+    pub rooms: Rooms,
+    pub auth: Auth,
+    pub storage: Storage,
+    pub log: Log,
+    pub filters: Filters,
+    pub retention: Retention,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    /// 行协议
+    pub native: SocketAddr,
+    pub irc: SocketAddr,
+    /// 行协议的 Unix domain socket，为空时不监听
+    pub unix: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_username_len: usize,
+    pub max_room_name_len: usize,
+    /// 每个用户离线信箱最多保存的私信
+    pub mailbox_quota: usize,
+    pub mailbox_ttl_days: u32,
+    pub search_page_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Delivery {
+    pub fanout: FanoutKind,
+    /// queue 分发方式下每个 peer 的队列大小
+    pub queue_size: usize,
+    /// broadcast 分发方式下所有 peer 共享的缓冲区大小
+    pub fanout_buffer: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rooms {
+    /// 所有用户登录后自动加入，不会因为没人而被删除
+    pub default: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub max_login_attempts: usize,
+    /// 没有设置时 /oper 命令不可用
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oper_password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    /// 聊天记录、账号、信箱、房间设置、webhook 配置和导出的文件都在这里
+    pub data_dir: PathBuf,
+    /// 聊天记录的一个日志段超过这个大小后开始写新的日志段
+    pub segment_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// off、error、warn、info、debug 或 trace
+    pub level: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// 不区分大小写，按整个单词匹配
+    pub blocked_words: Vec<String>,
+    pub blocked_patterns: Vec<String>,
+    /// 没有单独配置的房间，以及私信使用的策略
+    pub default: FilterPolicy,
+    /// 房间名到策略
+    pub rooms: BTreeMap<String, FilterPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterPolicy {
+    /// 按顺序执行
+    pub pipeline: Vec<FilterStage>,
+    pub on_blocked: BlockAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterStage {
+    /// 去掉 ANSI 转义序列、控制字符和双向文本控制符
+    Sanitize,
+    /// 屏蔽词和正则
+    Blocklist,
+    /// 遮住 API key、token 之类的密钥
+    Secrets,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// 后台压缩聊天记录的间隔，0 表示只在 operator 执行 /compact 时压缩
+    pub compact_interval_mins: u64,
+    /// 没有单独配置的房间使用的策略
+    pub default: RetentionPolicy,
+    /// 房间名到策略
+    pub rooms: BTreeMap<String, RetentionPolicy>,
+}
+
+/// 超过任意一个限制的旧消息会在压缩时删除，都没有设置时永久保留
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u32>,
+    /// 房间里最多保留的消息数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// 房间里保留的消息内容的总字节数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// 命中屏蔽词时拒绝整条消息，还是用 `*` 遮住命中的部分
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    #[default]
+    Reject,
+    Mask,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FanoutKind {
+    /// One bounded queue per peer
+    #[default]
+    Queue,
+    /// One ring buffer shared by all peers
+    Broadcast,
+}
+
+/// 覆盖配置的命令行参数，所有子命令通用
+#[derive(Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// TOML config file, defaults to $CHAT_CONFIG or ./chat.toml if it exists
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Address of the line protocol listener
+    #[arg(long, global = true)]
+    pub listen: Option<SocketAddr>,
+    /// Address of the IRC listener
+    #[arg(long, global = true)]
+    pub irc_listen: Option<SocketAddr>,
+    /// Path of the Unix domain socket, empty to disable it
+    #[arg(long, global = true)]
+    pub unix_socket: Option<PathBuf>,
+    /// Directory of the chat data
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// How messages are fanned out to peers
+    #[arg(long, value_enum, global = true)]
+    pub fanout: Option<FanoutKind>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    /// Override any setting, e.g. `--set limits.mailbox_quota=200`
+    #[arg(short, long = "set", value_name = "SECTION.KEY=VALUE", global = true)]
+    pub set: Vec<String>,
+    /// Print the effective configuration and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            native: SocketAddr::from(([0, 0, 0, 0], 8082)),
+            irc: SocketAddr::from(([0, 0, 0, 0], 6667)),
+            unix: PathBuf::from("tmp/chat/chat.sock"),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_username_len: 32,
+            max_room_name_len: 32,
+            mailbox_quota: 100,
+            mailbox_ttl_days: 7,
+            search_page_size: 10,
+        }
+    }
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self {
+            fanout: FanoutKind::default(),
+            queue_size: 128,
+            fanout_buffer: 4096,
+        }
+    }
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self {
+            default: "#general".to_string(),
+        }
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            max_login_attempts: 3,
+            oper_password: None,
+        }
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("tmp/chat"),
+            segment_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for FilterPolicy {
+    fn default() -> Self {
+        Self {
+            pipeline: vec![FilterStage::Sanitize, FilterStage::Blocklist],
+            on_blocked: BlockAction::default(),
+        }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            compact_interval_mins: 60,
+            default: RetentionPolicy::default(),
+            rooms: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// 合并所有来源并检查，出错时的信息指明是哪一个来源的哪一项
+    pub fn load(args: &ConfigArgs) -> anyhow::Result<Self> {
+        let path = args
+            .config
+            .clone()
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from))
+            .or_else(|| {
+                let path = Path::new(DEFAULT_CONFIG_PATH);
+                path.exists().then(|| path.to_path_buf())
+            });
+        let mut config = match &path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        //名字不是 UTF-8 的环境变量不可能是配置，跳过，env::vars() 遇到它们会 panic
+        for (name, value) in env::vars_os() {
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let Some(key) = env_key(name) else {
+                continue;
+            };
+            let Some(value) = value.to_str() else {
+                return Err(anyhow!("environment variable {name} is not valid UTF-8"));
+            };
+            config
+                .set(&key, value)
+                .with_context(|| format!("invalid environment variable {name}"))?;
+        }
+
+        config.apply(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 用 `section.key` 指定一项配置，按配置的类型解析
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "listen.native" => self.listen.native = parse(value)?,
+            "listen.irc" => self.listen.irc = parse(value)?,
+            "listen.unix" => self.listen.unix = PathBuf::from(value),
+            "limits.max_username_len" => self.limits.max_username_len = parse(value)?,
+            "limits.max_room_name_len" => self.limits.max_room_name_len = parse(value)?,
+            "limits.mailbox_quota" => self.limits.mailbox_quota = parse(value)?,
+            "limits.mailbox_ttl_days" => self.limits.mailbox_ttl_days = parse(value)?,
+            "limits.search_page_size" => self.limits.search_page_size = parse(value)?,
+            "delivery.fanout" => self.delivery.fanout = parse_enum(value)?,
+            "delivery.queue_size" => self.delivery.queue_size = parse(value)?,
+            "delivery.fanout_buffer" => self.delivery.fanout_buffer = parse(value)?,
+            "rooms.default" => self.rooms.default = value.to_string(),
+            "auth.max_login_attempts" => self.auth.max_login_attempts = parse(value)?,
+            "auth.oper_password" => self.auth.oper_password = Some(value.to_string()),
+            "storage.data_dir" => self.storage.data_dir = PathBuf::from(value),
+            "storage.segment_bytes" => self.storage.segment_bytes = parse(value)?,
+            "log.level" => self.log.level = value.to_string(),
+            "filters.blocked_words" => {
+                self.filters.blocked_words = split_list(value).map(String::from).collect()
+            }
+            "filters.pipeline" => {
+                self.filters.default.pipeline = split_list(value)
+                    .map(parse_enum)
+                    .collect::<Result<_, _>>()?
+            }
+            "filters.on_blocked" => self.filters.default.on_blocked = parse_enum(value)?,
+            "retention.compact_interval_mins" => {
+                self.retention.compact_interval_mins = parse(value)?
+            }
+            "retention.max_age_days" => self.retention.default.max_age_days = parse_option(value)?,
+            "retention.max_count" => self.retention.default.max_count = parse_option(value)?,
+            "retention.max_bytes" => self.retention.default.max_bytes = parse_option(value)?,
+            _ => anyhow::bail!("unknown setting {key}"),
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, args: &ConfigArgs) -> anyhow::Result<()> {
+        if let Some(addr) = args.listen {
+            self.listen.native = addr;
+        }
+        if let Some(addr) = args.irc_listen {
+            self.listen.irc = addr;
+        }
+        if let Some(path) = &args.unix_socket {
+            self.listen.unix = path.clone();
+        }
+        if let Some(dir) = &args.data_dir {
+            self.storage.data_dir = dir.clone();
+        }
+        if let Some(fanout) = args.fanout {
+            self.delivery.fanout = fanout;
+        }
+        if let Some(level) = &args.log_level {
+            self.log.level = level.clone();
+        }
+        for setting in &args.set {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow!("--set expects SECTION.KEY=VALUE, got {setting:?}"))?;
+            self.set(key.trim(), value.trim())
+                .with_context(|| format!("invalid --set {setting}"))?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let positive = [
+            ("limits.max_username_len", self.limits.max_username_len),
+            ("limits.max_room_name_len", self.limits.max_room_name_len),
+            ("limits.mailbox_quota", self.limits.mailbox_quota),
+            (
+                "limits.mailbox_ttl_days",
+                self.limits.mailbox_ttl_days as usize,
+            ),
+            ("limits.search_page_size", self.limits.search_page_size),
+            ("delivery.queue_size", self.delivery.queue_size),
+            ("delivery.fanout_buffer", self.delivery.fanout_buffer),
+            ("auth.max_login_attempts", self.auth.max_login_attempts),
+            ("storage.segment_bytes", self.storage.segment_bytes as usize),
+        ];
+        for (key, value) in positive {
+            ensure!(value > 0, "{key} must be greater than 0");
+        }
+        ensure!(
+            self.listen.native != self.listen.irc,
+            "listen.native and listen.irc must be different addresses"
+        );
+
+        let room = &self.rooms.default;
+        let name = room.strip_prefix('#').unwrap_or_default();
+        ensure!(
+            !name.is_empty()
+                && name.chars().count() <= self.limits.max_room_name_len
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-'),
+            "rooms.default must be '#' followed by 1 to {} letters, digits, '_' or '-', got {room:?}",
+            self.limits.max_room_name_len
+        );
+        ensure!(
+            self.auth
+                .oper_password
+                .as_ref()
+                .is_none_or(|p| !p.is_empty()),
+            "auth.oper_password must not be empty, leave it unset to disable /oper"
+        );
+        self.level()?;
+
+        for pattern in &self.filters.blocked_patterns {
+            regex::Regex::new(pattern)
+                .with_context(|| format!("invalid regex in filters.blocked_patterns: {pattern}"))?;
+        }
+        for room in self.filters.rooms.keys() {
+            ensure!(
+                room.starts_with('#'),
+                "filters.rooms must be keyed by room names starting with '#', got {room:?}"
+            );
+        }
+        for room in self.retention.rooms.keys() {
+            ensure!(
+                room.starts_with('#'),
+                "retention.rooms must be keyed by room names starting with '#', got {room:?}"
+            );
+        }
+        let policies = std::iter::once(("retention.default", &self.retention.default)).chain(
+            self.retention
+                .rooms
+                .iter()
+                .map(|(room, policy)| (room.as_str(), policy)),
+        );
+        for (name, policy) in policies {
+            ensure!(
+                policy.max_age_days != Some(0)
+                    && policy.max_count != Some(0)
+                    && policy.max_bytes != Some(0),
+                "retention limits of {name} must be greater than 0, leave them unset to keep messages forever"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn level(&self) -> anyhow::Result<LevelFilter> {
+        self.log.level.parse().map_err(|_| {
+            anyhow!(
+                "log.level must be off, error, warn, info, debug or trace, got {:?}",
+                self.log.level
+            )
+        })
+    }
+
+    /// chat1 只监听 TCP，用不到
+    #[allow(dead_code)]
+    pub fn unix_socket(&self) -> Option<&Path> {
+        let path = self.listen.unix.as_path();
+        (!path.as_os_str().is_empty()).then_some(path)
+    }
+
+    /// TOML 格式，密码用 `*` 代替
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let mut config = self.clone();
+        if let Some(password) = &mut config.auth.oper_password {
+            *password = "*".repeat(8);
+        }
+        Ok(toml::to_string_pretty(&config)?)
+    }
+
+    /// 加载好的配置只能设置一次
+    pub fn install(self) -> &'static Self {
+        if CONFIG.set(self).is_err() {
+            panic!("config is installed twice");
+        }
+        get()
+    }
+}
+
+/// 启动时 `Config::install` 之后才能调用
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config is installed at startup")
+}
+
+impl fmt::Display for FanoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Queue => write!(f, "queue"),
+            Self::Broadcast => write!(f, "broadcast"),
+        }
+    }
+}
+
+/// `CHAT_LIMITS_MAILBOX_QUOTA` 对应 `limits.mailbox_quota`，不是配置的环境变量返回 None
+fn env_key(name: &str) -> Option<String> {
+    if name == LEGACY_OPER_PASSWORD_ENV {
+        return Some("auth.oper_password".to_string());
+    }
+    let rest = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+    SECTIONS.iter().find_map(|section| {
+        let key = rest.strip_prefix(section)?.strip_prefix('_')?;
+        Some(format!("{section}.{key}"))
+    })
+}
+
+/// 逗号分隔，空字符串是空列表
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// 空字符串或者 `none` 表示不设置
+fn parse_option<T>(value: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    match value {
+        "" | "none" => Ok(None),
+        value => parse(value).map(Some),
+    }
+}
+
+fn parse_enum<T: ValueEnum>(value: &str) -> anyhow::Result<T> {
+    T::from_str(value, true).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|variant| variant.get_name().to_string())
+            .collect();
+        anyhow!("expected one of {}, got {value:?}", names.join(", "))
+    })
+}
+
+fn parse<T>(value: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow!("invalid value {value:?}: {e}"))
+}
@@ -0,0 +1,90 @@
+/*
+聊天 TUI 的公共布局，`sqlx_chat` 和 `chat_client` 共用
+    - 布局自上而下为：帮助信息、输入框、消息列表
+    - 传入用户列表时，消息列表右侧显示用户侧边栏
+*/
+
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Corner, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans, Text},
+    widgets::{Block, Borders, List, ListItem, Paragraph},
+    Frame,
+};
+use unicode_width::UnicodeWidthStr;
+
+const USER_LIST_WIDTH: u16 = 24;
+
+pub struct ChatView<'a> {
+    /// 帮助信息中的按键提示，(按键, 说明)
+    pub hints: &'a [(&'a str, &'a str)],
+    pub input: &'a str,
+    /// 消息列表标题，可用来显示连接状态
+    pub title: &'a str,
+    /// 按时间顺序排列的消息
+    pub messages: Vec<ListItem<'a>>,
+    /// 从最新消息往上滚动的条数，0 表示停在最底部
+    pub scroll: usize,
+    pub users: Option<Vec<ListItem<'a>>>,
+}
+
+pub fn draw<B: Backend>(frame: &mut Frame<B>, view: ChatView) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(2)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Length(3),
+                Constraint::Min(1),
+            ]
+            .as_ref(),
+        )
+        .split(frame.size());
+
+    let mut spans = vec![Span::raw("Press ")];
+    for (i, (key, action)) in view.hints.iter().enumerate() {
+        if i > 0 {
+            spans.push(Span::raw(", "));
+        }
+        spans.push(Span::styled(
+            *key,
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        spans.push(Span::raw(format!(" to {}", action)));
+    }
+    let help_message = Paragraph::new(Text::from(Spans::from(spans)));
+    frame.render_widget(help_message, chunks[0]);
+
+    let input = Paragraph::new(view.input)
+        .style(Style::default().fg(Color::Yellow))
+        .block(Block::default().borders(Borders::ALL).title("Input"));
+    frame.render_widget(input, chunks[1]);
+    frame.set_cursor(
+        // Put cursor past the end of the input text
+        chunks[1].x + view.input.width() as u16 + 1,
+        // Move one line down, from the border to the input line
+        chunks[1].y + 1,
+    );
+
+    let body = match view.users {
+        Some(_) => Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(1), Constraint::Length(USER_LIST_WIDTH)].as_ref())
+            .split(chunks[2]),
+        None => vec![chunks[2]],
+    };
+
+    //从底部开始渲染，新消息总是贴着底部，scroll 跳过最新的若干条
+    let messages: Vec<ListItem> = view.messages.into_iter().rev().skip(view.scroll).collect();
+    let messages = List::new(messages)
+        .start_corner(Corner::BottomLeft)
+        .block(Block::default().borders(Borders::ALL).title(view.title));
+    frame.render_widget(messages, body[0]);
+
+    if let Some(users) = view.users {
+        let users = List::new(users).block(Block::default().borders(Borders::ALL).title("Users"));
+        frame.render_widget(users, body[1]);
+    }
+}
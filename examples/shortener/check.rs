@@ -0,0 +1,503 @@
+/*
+存储的一致性检查
+    - `shortener check [STORE]...` 对每一种存储运行同样的检查，没有指定时检查所有存储
+    - 插入、按 ID 和 URL 查找、ID 和 URL 冲突、修改、自定义别名、过期清理、点击统计、分页列出、递增序号、删除
+    - postgres 使用 --database-url 指定的数据库，检查用的短链接带随机前缀，结束时删除
+    - file 使用临时文件，另外检查重新打开后数据、点击和序号是否保留
+    - `cargo test` 检查 memory 和 file，postgres 需要数据库，用 `cargo test --example shortener -- --ignored` 运行，
+      `SHORTENER_TEST_DATABASE_URL` 可以指定数据库
+*/
+
+use anyhow::{ensure, Context};
+use chrono::{DateTime, DurationRound, SubsecRound, TimeDelta, Utc};
+use std::{env, path::Path};
+use tokio::fs;
+
+use crate::{
+    clicks::{Bucket, BucketCount, Click, ReferrerCount},
+    ids::BASE62,
+    store::{FileStore, Inserted, MemoryStore, PgStore, StoreKind, Updated, UrlRecord, UrlStore},
+};
+
+/// 分页列出时每页的数量，小一点才能检查到跨页
+const PAGE_SIZE: usize = 2;
+
+pub async fn run(stores: &[StoreKind], database_url: &str) -> anyhow::Result<()> {
+    let stores = if stores.is_empty() {
+        &[StoreKind::Postgres, StoreKind::Memory, StoreKind::File]
+    } else {
+        stores
+    };
+    let mut failed = Vec::new();
+    for &kind in stores {
+        let result = match kind {
+            StoreKind::Postgres => match PgStore::connect(database_url).await {
+                Ok(store) => check(&store).await,
+                Err(e) => Err(e).with_context(|| format!("can not connect to {database_url}")),
+            },
+            StoreKind::Memory => check(&MemoryStore::default()).await,
+            StoreKind::File => check_file().await,
+        };
+        match result {
+            Ok(()) => println!("{kind:?}: ok"),
+            Err(e) => {
+                println!("{kind:?}: FAILED: {e:#}");
+                failed.push(kind);
+            }
+        }
+    }
+    ensure!(failed.is_empty(), "{failed:?} failed the checks");
+    Ok(())
+}
+
+/// 无论检查是否通过都删除检查用的短链接
+async fn check<S: UrlStore>(store: &S) -> anyhow::Result<()> {
+    let records = sample_records();
+    let result = check_records(store, &records).await;
+    for record in &records {
+        store.delete(&record.domain, &record.id).await?;
+    }
+    result
+}
+
+async fn check_records<S: UrlStore>(store: &S, records: &[UrlRecord; 3]) -> anyhow::Result<()> {
+    let [a, b, c] = records;
+
+    ensure!(
+        store.insert(a).await? == Inserted::Ok,
+        "inserting a new link"
+    );
+    ensure!(
+        store.get(&a.domain, &a.id).await?.as_ref() == Some(a),
+        "looking up a link by id"
+    );
+    ensure!(
+        store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "looking up a link by url"
+    );
+    ensure!(
+        store.get(&b.domain, &b.id).await?.is_none(),
+        "looking up a missing id"
+    );
+
+    let same_id = UrlRecord {
+        url: b.url.clone(),
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&same_id).await? == Inserted::IdTaken,
+        "inserting a taken id"
+    );
+    ensure!(
+        store.find_by_url(&b.domain, &b.url).await?.is_none(),
+        "a link with a taken id is not stored"
+    );
+    let same_url = UrlRecord {
+        url: a.url.clone(),
+        ..b.clone()
+    };
+    ensure!(
+        store.insert(&same_url).await? == Inserted::UrlExists(a.id.clone()),
+        "inserting a url that already has a link"
+    );
+    ensure!(
+        store.get(&b.domain, &b.id).await?.is_none(),
+        "a link with an existing url is not stored"
+    );
+
+    let alias = UrlRecord {
+        id: format!("{}-alias", a.id),
+        custom: true,
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&alias).await? == Inserted::Ok,
+        "inserting an alias for a url that already has a link"
+    );
+    ensure!(
+        store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "looking up a url finds its generated link, not an alias"
+    );
+    let taken_alias = UrlRecord {
+        url: b.url.clone(),
+        ..alias.clone()
+    };
+    ensure!(
+        store.insert(&taken_alias).await? == Inserted::IdTaken,
+        "inserting a taken alias"
+    );
+    ensure!(
+        store.delete(&alias.domain, &alias.id).await?,
+        "deleting an alias"
+    );
+    ensure!(
+        store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "deleting an alias keeps the generated link of its url"
+    );
+
+    //postgres 只保存到微秒
+    let redirected = UrlRecord {
+        id: format!("{}-redirected", a.id),
+        redirect_status: Some(301),
+        interstitial: true,
+        token_hash: Some(format!("{}-hash", a.id)),
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&redirected).await? == Inserted::Ok,
+        "inserting a link with its own redirect for a url that already has a link"
+    );
+    ensure!(
+        store
+            .get(&redirected.domain, &redirected.id)
+            .await?
+            .as_ref()
+            == Some(&redirected)
+            && store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "looking up a url finds its link with the default redirect"
+    );
+    ensure!(
+        store.delete(&redirected.domain, &redirected.id).await?,
+        "deleting a link with its own redirect"
+    );
+
+    let owned = UrlRecord {
+        id: format!("{}-owned", a.id),
+        token_hash: Some(format!("{}-hash", a.id)),
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&owned).await? == Inserted::Ok
+            && store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "a link with an owner is not deduplicated"
+    );
+    ensure!(
+        store.delete(&owned.domain, &owned.id).await?,
+        "deleting a link with an owner"
+    );
+
+    let moving = UrlRecord {
+        id: format!("{}-moving", a.id),
+        url: format!("{}/moving", a.url),
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&moving).await? == Inserted::Ok,
+        "inserting a link to update"
+    );
+    let moved = UrlRecord {
+        url: format!("{}/moved", a.url),
+        ..moving.clone()
+    };
+    ensure!(
+        store.update(&moved).await? == Updated::Ok,
+        "updating a link"
+    );
+    ensure!(
+        store.get(&moved.domain, &moved.id).await?.as_ref() == Some(&moved)
+            && store.find_by_url(&moved.domain, &moved.url).await?.as_ref() == Some(&moved)
+            && store
+                .find_by_url(&moving.domain, &moving.url)
+                .await?
+                .is_none(),
+        "updating the url of a link"
+    );
+    let onto_a = UrlRecord {
+        url: a.url.clone(),
+        ..moved.clone()
+    };
+    ensure!(
+        store.update(&onto_a).await? == Updated::UrlExists(a.id.clone()),
+        "updating a link to a url that already has a link"
+    );
+    let redirected_onto_a = UrlRecord {
+        redirect_status: Some(307),
+        ..onto_a.clone()
+    };
+    ensure!(
+        store.update(&redirected_onto_a).await? == Updated::Ok
+            && store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a)
+            && store
+                .find_by_url(&moved.domain, &moved.url)
+                .await?
+                .is_none(),
+        "updating a link so it is no longer deduplicated"
+    );
+    ensure!(
+        store.delete(&moving.domain, &moving.id).await?,
+        "deleting an updated link"
+    );
+    ensure!(
+        store.update(&moving).await? == Updated::Missing,
+        "updating a missing link"
+    );
+
+    let other = UrlRecord {
+        domain: format!("{}.check.invalid", a.id.to_lowercase()),
+        url: b.url.clone(),
+        ..a.clone()
+    };
+    ensure!(
+        store.insert(&other).await? == Inserted::Ok,
+        "inserting a taken id on another domain"
+    );
+    ensure!(
+        store.get(&a.domain, &a.id).await?.as_ref() == Some(a)
+            && store.get(&other.domain, &other.id).await?.as_ref() == Some(&other),
+        "looking up the same id on two domains"
+    );
+    ensure!(
+        store.find_by_url(&other.domain, &a.url).await?.is_none()
+            && store.find_by_url(&other.domain, &b.url).await?.as_ref() == Some(&other),
+        "looking up a url only finds links on the same domain"
+    );
+    ensure!(
+        store.delete(&other.domain, &other.id).await?
+            && store.get(&a.domain, &a.id).await?.is_some(),
+        "deleting a link keeps the same id on other domains"
+    );
+
+    let now = Utc::now().trunc_subsecs(0);
+    let expired = UrlRecord {
+        id: format!("{}-expired", a.id),
+        expires_at: Some(now - TimeDelta::hours(1)),
+        ..a.clone()
+    };
+    let expiring = UrlRecord {
+        id: format!("{}-expiring", a.id),
+        expires_at: Some(now + TimeDelta::hours(1)),
+        ..expired.clone()
+    };
+    ensure!(
+        store.insert(&expired).await? == Inserted::Ok
+            && store.insert(&expiring).await? == Inserted::Ok,
+        "inserting expiring links for a url that already has a link"
+    );
+    ensure!(
+        store.find_by_url(&a.domain, &a.url).await?.as_ref() == Some(a),
+        "looking up a url finds its permanent link, not an expiring one"
+    );
+    while store.purge_expired(now, PAGE_SIZE).await? == PAGE_SIZE {}
+    ensure!(
+        store.get(&expired.domain, &expired.id).await?.is_none(),
+        "purging deletes expired links"
+    );
+    ensure!(
+        store.get(&expiring.domain, &expiring.id).await?.as_ref() == Some(&expiring)
+            && store.get(&a.domain, &a.id).await?.as_ref() == Some(a),
+        "purging keeps links that have not expired"
+    );
+    ensure!(
+        store.delete(&expiring.domain, &expiring.id).await?,
+        "deleting an expiring link"
+    );
+
+    ensure!(store.insert(b).await? == Inserted::Ok, "inserting b");
+    ensure!(store.insert(c).await? == Inserted::Ok, "inserting c");
+    let hour = Utc::now().duration_trunc(TimeDelta::hours(1))?;
+    let click = |id: &str, at: DateTime<Utc>, referrer: Option<&str>| Click {
+        domain: a.domain.clone(),
+        id: id.to_string(),
+        at,
+        referrer: referrer.map(str::to_string),
+        user_agent: Some("check".to_string()),
+        ip_hash: "0123456789abcdef".to_string(),
+    };
+    store
+        .record_clicks(&[
+            click(&a.id, hour, Some("https://x.invalid/")),
+            click(
+                &a.id,
+                hour + TimeDelta::minutes(1),
+                Some("https://x.invalid/"),
+            ),
+            click(
+                &a.id,
+                hour + TimeDelta::hours(2),
+                Some("https://y.invalid/"),
+            ),
+            click(&b.id, hour, None),
+            click(&format!("{}-missing", a.id), hour, None),
+        ])
+        .await?;
+    let stats = store.click_stats(&a.domain, &a.id, Bucket::Hour, 1).await?;
+    ensure!(stats.total == 3, "counting the clicks of a link");
+    ensure!(
+        stats.histogram
+            == [
+                BucketCount {
+                    start: hour,
+                    clicks: 2
+                },
+                BucketCount {
+                    start: hour + TimeDelta::hours(2),
+                    clicks: 1
+                }
+            ],
+        "counting clicks per hour"
+    );
+    ensure!(
+        stats.top_referrers
+            == [ReferrerCount {
+                referrer: "https://x.invalid/".to_string(),
+                clicks: 2
+            }],
+        "listing the top referrers"
+    );
+    ensure!(
+        store
+            .click_stats(&a.domain, &format!("{}-missing", a.id), Bucket::Day, 10)
+            .await?
+            .total
+            == 0,
+        "dropping the clicks of a missing link"
+    );
+
+    let mut listed = Vec::new();
+    loop {
+        let page = store.list(listed.len(), PAGE_SIZE).await?;
+        ensure!(page.len() <= PAGE_SIZE, "listing at most `limit` links");
+        let done = page.len() < PAGE_SIZE;
+        listed.extend(page);
+        if done {
+            break;
+        }
+    }
+    ensure!(
+        listed
+            .windows(2)
+            .all(|pair| (&pair[0].domain, &pair[0].id) < (&pair[1].domain, &pair[1].id)),
+        "listing links in domain and id order without duplicates"
+    );
+    let ours: Vec<&UrlRecord> = listed
+        .iter()
+        .filter(|record| records.contains(record))
+        .collect();
+    ensure!(ours == [a, b, c], "listing every link");
+
+    let first = store.next_sequence().await?;
+    let second = store.next_sequence().await?;
+    ensure!(first > 0 && second > first, "sequence increases");
+
+    ensure!(store.delete(&b.domain, &b.id).await?, "deleting a link");
+    ensure!(
+        !store.delete(&b.domain, &b.id).await?,
+        "deleting a missing link"
+    );
+    ensure!(
+        store.get(&b.domain, &b.id).await?.is_none()
+            && store.find_by_url(&b.domain, &b.url).await?.is_none(),
+        "a deleted link is gone"
+    );
+    ensure!(
+        store.insert(b).await? == Inserted::Ok,
+        "the id and url of a deleted link can be used again"
+    );
+    ensure!(
+        store
+            .click_stats(&b.domain, &b.id, Bucket::Day, 10)
+            .await?
+            .total
+            == 0,
+        "deleting a link deletes its clicks"
+    );
+    Ok(())
+}
+
+async fn check_file() -> anyhow::Result<()> {
+    let path = env::temp_dir().join(format!("shortener-check-{}.jsonl", nanoid::nanoid!()));
+    let result = check_file_at(&path).await;
+    let _ = fs::remove_file(&path).await;
+    result
+}
+
+async fn check_file_at(path: &Path) -> anyhow::Result<()> {
+    check(&FileStore::open(path).await?).await?;
+
+    let [a, b, _] = sample_records();
+    let store = FileStore::open(path).await?;
+    store.insert(&a).await?;
+    store.insert(&b).await?;
+    store.delete(&a.domain, &a.id).await?;
+    let b = UrlRecord {
+        url: format!("{}/updated", b.url),
+        interstitial: true,
+        ..b
+    };
+    store.update(&b).await?;
+    store
+        .record_clicks(&[Click {
+            domain: b.domain.clone(),
+            id: b.id.clone(),
+            at: Utc::now(),
+            referrer: None,
+            user_agent: None,
+            ip_hash: "0123456789abcdef".to_string(),
+        }])
+        .await?;
+    let sequence = store.next_sequence().await?;
+    drop(store);
+
+    let store = FileStore::open(path).await?;
+    ensure!(
+        store.get(&a.domain, &a.id).await?.is_none()
+            && store.get(&b.domain, &b.id).await?.as_ref() == Some(&b),
+        "reopening the file keeps inserts, updates and deletes"
+    );
+    ensure!(
+        store
+            .click_stats(&b.domain, &b.id, Bucket::Day, 10)
+            .await?
+            .total
+            == 1,
+        "reopening the file keeps clicks"
+    );
+    ensure!(
+        store.next_sequence().await? > sequence,
+        "reopening the file continues the sequence"
+    );
+    Ok(())
+}
+
+/// 随机前缀的三个短链接，ID 按顺序排列
+fn sample_records() -> [UrlRecord; 3] {
+    let alphabet: Vec<char> = BASE62.chars().collect();
+    let prefix = nanoid::format(nanoid::rngs::default, &alphabet, 5);
+    ["a", "b", "c"].map(|suffix| UrlRecord {
+        domain: String::new(),
+        id: format!("{prefix}{suffix}"),
+        url: format!("https://check.invalid/{prefix}/{suffix}"),
+        custom: false,
+        expires_at: None,
+        redirect_status: None,
+        interstitial: false,
+        token_hash: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DATABASE_URL_ENV: &str = "SHORTENER_TEST_DATABASE_URL";
+
+    #[tokio::test]
+    async fn memory_store() {
+        check(&MemoryStore::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_file_at(&dir.path().join("urls.jsonl")).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database"]
+    async fn postgres_store() {
+        let url =
+            env::var(TEST_DATABASE_URL_ENV).unwrap_or_else(|_| crate::CONNECT_URL.to_string());
+        let store = PgStore::connect(&url).await.unwrap();
+        check(&store).await.unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+/*
+点击统计
+    - 每次重定向记录一次点击：时间、来源页面、User-Agent 和客户端 IP 的哈希，不保存原始 IP
+    - 重定向只把点击放进有界的 channel，不等待存储，channel 满了就丢弃并告警
+    - 后台任务把点击攒成一批，批满了或者到了 `--click-flush-secs` 时一次写入存储
+    - GET /:id/stats 返回总点击数、按小时或者按天的直方图和点击最多的来源页面，只包括已经写入的点击
+*/
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    time,
+};
+use tracing::warn;
+
+use crate::store::UrlStore;
+
+/// 来源页面和 User-Agent 最多保存的字符数
+const MAX_HEADER_LEN: usize = 512;
+/// IP 哈希保留的十六进制字符数
+const IP_HASH_LEN: usize = 16;
+pub const DEFAULT_TOP_REFERRERS: usize = 10;
+
+#[derive(Debug, Args)]
+pub struct ClickArgs {
+    /// Seconds between two flushes of buffered clicks
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    click_flush_secs: u64,
+    /// Clicks written to the store per batch
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+    click_batch_size: u64,
+    /// Clicks buffered before new ones are dropped
+    #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+    click_buffer: u64,
+    /// Salt of client IP hashes, a random one per run when not set
+    #[arg(long)]
+    ip_salt: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Click {
+    #[serde(default)]
+    pub domain: String,
+    pub id: String,
+    pub at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    pub ip_hash: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ClickStats {
+    pub total: u64,
+    /// 按时间排序，没有点击的时间段不出现
+    pub histogram: Vec<BucketCount>,
+    /// 按点击数从多到少排序，不包括没有来源页面的点击
+    pub top_referrers: Vec<ReferrerCount>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct BucketCount {
+    pub start: DateTime<Utc>,
+    pub clicks: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ReferrerCount {
+    pub referrer: String,
+    pub clicks: u64,
+}
+
+/// 在 handler 之间共享，clone 只是 clone channel 的发送端
+#[derive(Debug, Clone)]
+pub struct ClickRecorder {
+    tx: Sender<Click>,
+    salt: Arc<[u8; 32]>,
+}
+
+impl Bucket {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+
+    pub fn start(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let width = match self {
+            Bucket::Hour => TimeDelta::hours(1),
+            Bucket::Day => TimeDelta::days(1),
+        };
+        at.duration_trunc(width).unwrap_or(at)
+    }
+}
+
+impl ClickStats {
+    /// memory 和 file 存储在内存中统计，postgres 用 SQL 统计
+    pub fn from_clicks<'a>(
+        clicks: impl IntoIterator<Item = &'a Click>,
+        bucket: Bucket,
+        top: usize,
+    ) -> Self {
+        let mut total = 0;
+        let mut histogram = BTreeMap::new();
+        let mut referrers: BTreeMap<&str, u64> = BTreeMap::new();
+        for click in clicks {
+            total += 1;
+            *histogram.entry(bucket.start(click.at)).or_insert(0) += 1;
+            if let Some(referrer) = &click.referrer {
+                *referrers.entry(referrer).or_insert(0) += 1;
+            }
+        }
+        let mut top_referrers: Vec<ReferrerCount> = referrers
+            .into_iter()
+            .map(|(referrer, clicks)| ReferrerCount {
+                referrer: referrer.to_string(),
+                clicks,
+            })
+            .collect();
+        //点击数相同时按来源页面排序，和 postgres 的结果一致
+        top_referrers.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.referrer.cmp(&b.referrer)));
+        top_referrers.truncate(top);
+        Self {
+            total,
+            histogram: histogram
+                .into_iter()
+                .map(|(start, clicks)| BucketCount { start, clicks })
+                .collect(),
+            top_referrers,
+        }
+    }
+}
+
+impl Click {
+    pub fn key(&self) -> (String, String) {
+        (self.domain.clone(), self.id.clone())
+    }
+}
+
+impl ClickRecorder {
+    /// 启动写入点击的后台任务
+    pub fn spawn<S: UrlStore>(store: Arc<S>, args: &ClickArgs) -> Self {
+        let (tx, rx) = mpsc::channel(args.click_buffer as usize);
+        let salt = match &args.ip_salt {
+            Some(salt) => salt.clone(),
+            None => nanoid::nanoid!(32),
+        };
+        tokio::spawn(flush_periodically(
+            store,
+            rx,
+            args.click_batch_size as usize,
+            Duration::from_secs(args.click_flush_secs),
+        ));
+        Self {
+            tx,
+            salt: Arc::new(blake3::derive_key("shortener client ip", salt.as_bytes())),
+        }
+    }
+
+    /// 不等待，存储跟不上时丢弃点击
+    pub fn record(
+        &self,
+        domain: &str,
+        id: &str,
+        referrer: Option<&str>,
+        user_agent: Option<&str>,
+        ip: IpAddr,
+    ) {
+        let click = Click {
+            domain: domain.to_string(),
+            id: id.to_string(),
+            at: Utc::now(),
+            referrer: referrer.map(truncate),
+            user_agent: user_agent.map(truncate),
+            ip_hash: self.hash_ip(ip),
+        };
+        match self.tx.try_send(click) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Click buffer is full, dropping a click on {id}"),
+            Err(TrySendError::Closed(_)) => {
+                warn!("Click writer has stopped, dropping a click on {id}")
+            }
+        }
+    }
+
+    fn hash_ip(&self, ip: IpAddr) -> String {
+        //IPv4 映射的 IPv6 地址和 IPv4 地址是同一个客户端
+        let ip = ip.to_canonical().to_string();
+        let hash = blake3::keyed_hash(&self.salt, ip.as_bytes()).to_hex();
+        hash[..IP_HASH_LEN].to_string()
+    }
+}
+
+async fn flush_periodically<S: UrlStore>(
+    store: Arc<S>,
+    mut rx: Receiver<Click>,
+    batch: usize,
+    period: Duration,
+) {
+    let mut interval = time::interval(period);
+    let mut buffer = Vec::with_capacity(batch);
+    loop {
+        let closed = tokio::select! {
+            click = rx.recv() => match click {
+                Some(click) => {
+                    buffer.push(click);
+                    if buffer.len() < batch {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = interval.tick() => false,
+        };
+        if !buffer.is_empty() {
+            //写入失败的点击直接丢弃，重试会让缓冲区越积越多
+            if let Err(e) = store.record_clicks(&buffer).await {
+                warn!("Failed to record {} clicks: {e}", buffer.len());
+            }
+            buffer.clear();
+        }
+        if closed {
+            break;
+        }
+    }
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_HEADER_LEN).collect()
+}
@@ -0,0 +1,136 @@
+/*
+短链接的域名
+    - `--base-url` 是返回给用户的短链接的前缀，服务在代理后面时设置成代理的地址，可以带路径
+    - 可以指定多个 `--base-url`，一个服务同时服务多个短域名，按请求的 Host 头选择域名
+    - 每个域名的短链接互相独立，同一个 ID 可以在两个域名上指向不同的 URL
+    - 第一个 `--base-url` 是默认域名，Host 头不是任何一个域名时使用它，之前创建的短链接也都属于它
+*/
+
+use anyhow::{ensure, Context};
+use clap::Args;
+use std::collections::BTreeMap;
+use url::Url;
+
+#[derive(Debug, Args)]
+pub struct DomainArgs {
+    /// Public base URL of short links, repeat it to serve several short domains
+    #[arg(long = "base-url")]
+    base_urls: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Domains {
+    default: Url,
+    /// `host[:port]` 到默认域名以外的域名
+    others: BTreeMap<String, Url>,
+}
+
+/// 请求所属的域名
+#[derive(Debug, Clone, Copy)]
+pub struct Domain<'a> {
+    /// 存储中的域名，默认域名是空字符串
+    pub key: &'a str,
+    base: &'a Url,
+}
+
+impl Domains {
+    /// 没有指定 `--base-url` 时使用 `default`
+    pub fn try_new(args: &DomainArgs, default: &str) -> anyhow::Result<Self> {
+        let mut base_urls = Vec::new();
+        for raw in &args.base_urls {
+            base_urls
+                .push(parse_base_url(raw).with_context(|| format!("invalid --base-url {raw}"))?);
+        }
+        if base_urls.is_empty() {
+            base_urls.push(parse_base_url(default)?);
+        }
+        let default = base_urls.remove(0);
+        let mut others = BTreeMap::new();
+        for url in base_urls {
+            let key = origin(&url);
+            ensure!(
+                key != origin(&default) && !others.contains_key(&key),
+                "--base-url {url} repeats the domain {key}"
+            );
+            others.insert(key, url);
+        }
+        Ok(Self { default, others })
+    }
+
+    /// 按 Host 头选择域名，只有一个域名时忽略 Host 头
+    pub fn resolve(&self, host: Option<&str>) -> Domain<'_> {
+        let found = host
+            .map(normalize_host)
+            .and_then(|host| self.others.get_key_value(&host));
+        match found {
+            Some((key, base)) => Domain { key, base },
+            None => Domain {
+                key: "",
+                base: &self.default,
+            },
+        }
+    }
+
+    /// 所有域名的 `host:port`，用于发现重定向循环
+    pub fn origins(&self) -> Vec<String> {
+        std::iter::once(&self.default)
+            .chain(self.others.values())
+            .filter_map(|url| {
+                let port = url.port_or_known_default()?;
+                Some(format!("{}:{port}", url.host_str()?))
+            })
+            .collect()
+    }
+
+    pub fn base_urls(&self) -> impl Iterator<Item = &Url> {
+        std::iter::once(&self.default).chain(self.others.values())
+    }
+}
+
+impl Domain<'_> {
+    pub fn link(&self, id: &str) -> String {
+        format!("{}{id}", self.base)
+    }
+}
+
+/// 路径总是以 `/` 结尾，短链接的 ID 直接接在后面
+fn parse_base_url(raw: &str) -> anyhow::Result<Url> {
+    let mut url = Url::parse(raw)?;
+    ensure!(
+        matches!(url.scheme(), "http" | "https") && url.host().is_some(),
+        "a base URL must be an http or https URL with a host"
+    );
+    ensure!(
+        url.query().is_none() && url.fragment().is_none(),
+        "a base URL can not have a query or fragment"
+    );
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// 和 Host 头一样的格式，默认端口不写
+fn origin(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+/// Host 头不区分大小写，可以以 `.` 结尾，可以带上默认端口
+fn normalize_host(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    let (name, port) = match host.rsplit_once(':') {
+        //IPv6 地址中的 `:` 不是端口
+        Some((name, port)) if !port.contains(']') => (name, Some(port)),
+        _ => (host.as_str(), None),
+    };
+    let name = name.trim_end_matches('.');
+    match port {
+        Some("80" | "443") | None => name.to_string(),
+        Some(port) => format!("{name}:{port}"),
+    }
+}
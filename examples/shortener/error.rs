@@ -0,0 +1,168 @@
+/*
+API 的错误
+    - 响应体是 `{"error": {"code": "...", "message": "..."}}`，`code` 是给程序判断的，`message` 是给人看的
+    - 找不到短链接 404，过期 410，请求格式错误 400，内容不合法 422，冲突 409，存储不可用 503
+    - 没有 token 401，token 不对 403
+    - 存储的错误只记录日志（包括底层的原因），不把 SQL 和内部信息返回给客户端
+*/
+
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::error::Error as _;
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Error, Debug)]
+pub enum Errors {
+    // #[error("Can not connect to database:{0}")]
+    // ConnectedError(String),
+    // #[error("Can not get redirect url:{0}")]
+    // RedirectError(String),
+    #[error("Sql executed error:{0}")]
+    ExecuteError(String, #[source] sqlx::Error),
+    #[error("Storage error:{0}")]
+    StorageError(String),
+    #[error("Could not generate a unique id after {0} attempts")]
+    IdExhausted(usize),
+    #[error("Invalid alias: {0}")]
+    InvalidAlias(String),
+    #[error("Alias {0} is already taken")]
+    AliasTaken(String),
+    #[error("Invalid expiry: {0}")]
+    InvalidExpiry(String),
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("Links to {0} are not allowed")]
+    BlockedUrl(String),
+    #[error("Short link {0} does not exist")]
+    NotFound(String),
+    #[error("Short link {0} has expired")]
+    Expired(String),
+    /// 请求体或者查询参数不能解析
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// 请求可以解析，但是字段的类型或者取值不对
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Short link {0} points to an invalid url")]
+    BrokenLink(String),
+    #[error("Redirect status {0} is not one of 301, 302, 307 and 308")]
+    InvalidRedirect(u16),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("The token does not own short link {0}")]
+    Forbidden(String),
+    #[error("Url already has the short link {0}")]
+    UrlTaken(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+    code: &'static str,
+    message: String,
+}
+
+impl Errors {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Errors::ExecuteError(..) | Errors::StorageError(_) | Errors::IdExhausted(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Errors::InvalidAlias(_)
+            | Errors::InvalidExpiry(_)
+            | Errors::InvalidUrl(_)
+            | Errors::BlockedUrl(_)
+            | Errors::InvalidRedirect(_)
+            | Errors::InvalidRequest(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Errors::AliasTaken(_) | Errors::UrlTaken(_) => StatusCode::CONFLICT,
+            Errors::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Errors::Forbidden(_) => StatusCode::FORBIDDEN,
+            Errors::NotFound(_) => StatusCode::NOT_FOUND,
+            Errors::Expired(_) => StatusCode::GONE,
+            Errors::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Errors::BrokenLink(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// 稳定的错误码，客户端用它而不是 `message` 判断错误
+    pub fn code(&self) -> &'static str {
+        match self {
+            Errors::ExecuteError(..) | Errors::StorageError(_) => "storage_unavailable",
+            Errors::IdExhausted(_) => "id_exhausted",
+            Errors::InvalidAlias(_) => "invalid_alias",
+            Errors::AliasTaken(_) => "alias_taken",
+            Errors::InvalidExpiry(_) => "invalid_expiry",
+            Errors::InvalidUrl(_) => "invalid_url",
+            Errors::BlockedUrl(_) => "blocked_url",
+            Errors::NotFound(_) => "not_found",
+            Errors::Expired(_) => "expired",
+            Errors::BadRequest(_) => "bad_request",
+            Errors::InvalidRequest(_) => "invalid_request",
+            Errors::BrokenLink(_) => "broken_link",
+            Errors::InvalidRedirect(_) => "invalid_redirect",
+            Errors::Unauthorized(_) => "unauthorized",
+            Errors::Forbidden(_) => "forbidden",
+            Errors::UrlTaken(_) => "url_exists",
+        }
+    }
+}
+
+impl IntoResponse for Errors {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let code = self.code();
+        let message = match &self {
+            // Errors::ConnectedError(err)=>err.into_response(),
+            // Errors::RedirectError(err)=>err.into_response(),
+            Errors::ExecuteError(..) | Errors::StorageError(_) => {
+                match self.source() {
+                    Some(source) => warn!("Storage failed: {self}: {source}"),
+                    None => warn!("Storage failed: {self}"),
+                }
+                "The storage is unavailable, try again later".to_string()
+            }
+            _ => {
+                if status.is_server_error() {
+                    warn!("{self}");
+                }
+                self.to_string()
+            }
+        };
+        let body = ErrorBody {
+            error: ErrorDetail { code, message },
+        };
+        let mut response = (status, Json(body)).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// 字段缺失或者类型不对是 422，其他的（不是 JSON、没有 Content-Type）是 400
+impl From<JsonRejection> for Errors {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::JsonDataError(e) => Errors::InvalidRequest(e.body_text()),
+            e => Errors::BadRequest(e.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for Errors {
+    fn from(rejection: QueryRejection) -> Self {
+        Errors::BadRequest(rejection.body_text())
+    }
+}
@@ -0,0 +1,246 @@
+/*
+生成短 ID
+    - nanoid：指定字符集和长度的随机 ID
+    - sequence：存储提供的递增序号的 base62 编码，左边补 0 到指定长度
+    - hash：URL 的 blake3 哈希的 base62 编码，最长 21 位
+        - 按 URL 去重的短链接同一个 URL 总是得到同一个 ID
+        - 不按 URL 去重的短链接（有过期时间、重定向方式、中间页或者管理 token）在哈希中加入随机数，
+          否则同一个 URL 的第 N 个这样的短链接要和之前的 N - 1 个冲突
+    - ID 已经被占用时用下一次尝试的序号重新生成，hash 策略把序号加入哈希
+    - 用户也可以指定别名：字母、数字、`-` 和 `_`，不能和 API 的路由同名
+*/
+
+use anyhow::ensure;
+use clap::{Args, ValueEnum};
+use std::collections::BTreeSet;
+
+use crate::{
+    error::Errors,
+    store::{UrlRecord, UrlStore},
+};
+
+/// urls.id 的长度，生成的 ID 和别名都不能超过
+pub const ID_COLUMN_LEN: usize = 64;
+/// 生成的 ID 的默认长度
+const DEFAULT_ID_LEN: usize = 6;
+/// u128 的 base62 编码最多 22 位，最高一位只有 0 到 7，只取低 21 位才是均匀的
+const MAX_HASH_ID_LEN: usize = 21;
+const U128_BASE62_LEN: usize = 22;
+const MIN_ALIAS_LEN: usize = 3;
+/// 不区分大小写，包括现有的和以后可能增加的路由
+const RESERVED_ALIASES: &[&str] = &[
+    "api", "admin", "assets", "docs", "health", "healthz", "login", "logout", "metrics", "new",
+    "shorten", "static", "stats", "urls",
+];
+pub const BASE62: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Debug, Args)]
+pub struct IdArgs {
+    /// How new short IDs are generated
+    #[arg(long, value_enum, default_value_t = IdStrategy::Nanoid)]
+    id_strategy: IdStrategy,
+    /// Characters of random IDs
+    #[arg(long, default_value = BASE62)]
+    id_alphabet: String,
+    /// Length of generated IDs
+    #[arg(long, default_value_t = DEFAULT_ID_LEN)]
+    id_length: usize,
+    /// Give up after this many ID collisions
+    #[arg(long, default_value_t = 5)]
+    max_id_attempts: usize,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum IdStrategy {
+    /// Random nanoid
+    Nanoid,
+    /// Base62 of a sequence kept by the store
+    Sequence,
+    /// Base62 of the blake3 hash of the URL, salted for links that are not deduplicated
+    Hash,
+}
+
+#[derive(Debug)]
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    alphabet: Vec<char>,
+    length: usize,
+    pub max_attempts: usize,
+}
+
+impl IdGenerator {
+    pub fn try_new(args: &IdArgs) -> anyhow::Result<Self> {
+        let alphabet: Vec<char> = args.id_alphabet.chars().collect();
+        let unique: BTreeSet<char> = alphabet.iter().copied().collect();
+        ensure!(
+            unique.len() == alphabet.len() && (2..=255).contains(&alphabet.len()),
+            "--id-alphabet must have 2 to 255 distinct characters"
+        );
+        //RFC 3986 中不需要编码的字符，去掉 `.`，ID 不能是 `.` 和 `..`
+        ensure!(
+            alphabet
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '~')),
+            "--id-alphabet may only contain letters, digits, '-', '_' and '~'"
+        );
+        ensure!(
+            (1..=ID_COLUMN_LEN).contains(&args.id_length),
+            "--id-length must be between 1 and {ID_COLUMN_LEN}"
+        );
+        ensure!(
+            !matches!(args.id_strategy, IdStrategy::Hash) || args.id_length <= MAX_HASH_ID_LEN,
+            "--id-length must be at most {MAX_HASH_ID_LEN} with --id-strategy hash"
+        );
+        ensure!(
+            args.max_id_attempts > 0,
+            "--max-id-attempts must be greater than 0"
+        );
+        Ok(Self {
+            strategy: args.id_strategy,
+            alphabet,
+            length: args.id_length,
+            max_attempts: args.max_id_attempts,
+        })
+    }
+
+    /// 第 `attempt` 次（从 0 开始）为 `record` 生成 ID
+    pub async fn generate<S: UrlStore>(
+        &self,
+        store: &S,
+        record: &UrlRecord,
+        attempt: usize,
+    ) -> Result<String, Errors> {
+        let sequence = match self.strategy {
+            IdStrategy::Sequence => store.next_sequence().await?,
+            _ => 0,
+        };
+        let nonce = match self.strategy {
+            IdStrategy::Hash if !record.deduplicated() => Some(nanoid::nanoid!()),
+            _ => None,
+        };
+        Ok(self.format(&record.url, nonce.as_deref(), attempt, sequence))
+    }
+
+    /// `nonce` 只在 hash 策略下使用，`sequence` 只在 sequence 策略下使用
+    fn format(&self, url: &str, nonce: Option<&str>, attempt: usize, sequence: u64) -> String {
+        match self.strategy {
+            IdStrategy::Nanoid => {
+                nanoid::format(nanoid::rngs::default, &self.alphabet, self.length)
+            }
+            //左边补 0 到固定长度，超过长度时保留完整的编码
+            IdStrategy::Sequence => {
+                let id = base62(sequence as u128);
+                format!("{id:0>width$}", width = self.length)
+            }
+            IdStrategy::Hash => {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(url.as_bytes());
+                if let Some(nonce) = nonce {
+                    hasher.update(nonce.as_bytes());
+                }
+                if attempt > 0 {
+                    hasher.update(&attempt.to_le_bytes());
+                }
+                let hash = hasher.finalize();
+                let bytes: [u8; 16] = hash.as_bytes()[..16].try_into().unwrap();
+                //最高位的分布不均匀，取最低的几位，补 0 到固定长度后编码比较短的哈希也能截取
+                let id = base62(u128::from_le_bytes(bytes));
+                let id = format!("{id:0>U128_BASE62_LEN$}");
+                id[U128_BASE62_LEN - self.length..].to_string()
+            }
+        }
+    }
+}
+
+pub fn validate_alias(alias: &str) -> Result<(), Errors> {
+    let len = alias.chars().count();
+    if !(MIN_ALIAS_LEN..=ID_COLUMN_LEN).contains(&len) {
+        return Err(Errors::InvalidAlias(format!(
+            "alias must be {MIN_ALIAS_LEN} to {ID_COLUMN_LEN} characters long"
+        )));
+    }
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Errors::InvalidAlias(
+            "alias may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    if RESERVED_ALIASES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(alias))
+    {
+        return Err(Errors::InvalidAlias(format!("alias {alias} is reserved")));
+    }
+    Ok(())
+}
+
+fn base62(mut n: u128) -> String {
+    let digits = BASE62.as_bytes();
+    let mut id = Vec::new();
+    loop {
+        id.push(digits[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    id.reverse();
+    String::from_utf8(id).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(strategy: IdStrategy, length: usize) -> IdArgs {
+        IdArgs {
+            id_strategy: strategy,
+            id_alphabet: BASE62.to_string(),
+            id_length: length,
+            max_id_attempts: 5,
+        }
+    }
+
+    #[test]
+    fn hash_ids_have_the_requested_length() {
+        for length in 1..=MAX_HASH_ID_LEN {
+            let ids = IdGenerator::try_new(&args(IdStrategy::Hash, length)).unwrap();
+            for n in 0..200 {
+                let url = format!("https://example.com/{n}");
+                assert_eq!(ids.format(&url, None, 0, 0).len(), length);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_ids_can_not_be_longer_than_the_hash() {
+        assert!(IdGenerator::try_new(&args(IdStrategy::Hash, MAX_HASH_ID_LEN + 1)).is_err());
+        assert!(IdGenerator::try_new(&args(IdStrategy::Nanoid, ID_COLUMN_LEN)).is_ok());
+    }
+
+    #[test]
+    fn alphabets_must_be_safe_in_paths() {
+        let alphabet = |alphabet: &str| IdArgs {
+            id_alphabet: alphabet.to_string(),
+            ..args(IdStrategy::Nanoid, DEFAULT_ID_LEN)
+        };
+        assert!(IdGenerator::try_new(&alphabet("abcXYZ019-_~")).is_ok());
+        for unsafe_char in ['/', '?', '#', '%', '\\', '&', '+', '.', ' ', 'é'] {
+            let args = alphabet(&format!("abc{unsafe_char}"));
+            assert!(
+                IdGenerator::try_new(&args).is_err(),
+                "{unsafe_char:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn small_numbers_are_padded() {
+        assert_eq!(base62(0), "0");
+        assert_eq!(base62(u128::MAX).len(), U128_BASE62_LEN);
+        let ids = IdGenerator::try_new(&args(IdStrategy::Sequence, 4)).unwrap();
+        assert_eq!(ids.format("", None, 0, 62), "0010");
+    }
+}
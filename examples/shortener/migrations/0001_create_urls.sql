@@ -0,0 +1,7 @@
+-- 引入迁移之前由服务启动时创建，已经存在时跳过
+create table if not exists urls(
+    id char(6) primary key,
+    url text not null unique
+);
+
+create sequence if not exists url_id_seq;
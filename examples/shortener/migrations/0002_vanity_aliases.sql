@@ -0,0 +1,8 @@
+-- 自定义别名比生成的 ID 长，同一个 URL 可以有多个别名，只有生成的 ID 仍然按 URL 去重
+alter table urls alter column id type varchar(64);
+
+alter table urls add column custom boolean not null default false;
+
+alter table urls drop constraint if exists urls_url_key;
+
+create unique index urls_generated_url on urls(url) where not custom;
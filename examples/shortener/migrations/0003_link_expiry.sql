@@ -0,0 +1,8 @@
+-- 有过期时间的短链接总是新建，不和永久的短链接按 URL 去重
+alter table urls add column expires_at timestamptz;
+
+drop index urls_generated_url;
+
+create unique index urls_generated_url on urls(url) where not custom and expires_at is null;
+
+create index urls_expires_at on urls(expires_at) where expires_at is not null;
@@ -0,0 +1,10 @@
+-- 点击由后台任务分批写入，删除短链接时一起删除
+create table if not exists clicks(
+    id bigserial primary key,
+    link_id varchar(64) not null references urls(id) on delete cascade,
+    at timestamptz not null,
+    referrer text,
+    user_agent text,
+    ip_hash text not null
+);
+create index if not exists clicks_link_id_at on clicks(link_id, at);
@@ -0,0 +1,11 @@
+-- 短链接按域名分开，同一个 ID 可以在不同的域名上使用，之前的短链接属于默认域名 ''
+alter table urls add column domain varchar(253) not null default '';
+alter table clicks add column domain varchar(253) not null default '';
+alter table clicks drop constraint clicks_link_id_fkey;
+alter table urls drop constraint urls_pkey;
+alter table urls add primary key (domain, id);
+alter table clicks add foreign key (domain, link_id) references urls(domain, id) on delete cascade;
+drop index urls_generated_url;
+create unique index urls_generated_url on urls(domain, url) where not custom and expires_at is null;
+drop index clicks_link_id_at;
+create index clicks_link_id_at on clicks(domain, link_id, at);
@@ -0,0 +1,6 @@
+-- 每个短链接可以选择重定向的状态码和中间页，null 表示使用服务的默认状态码
+alter table urls add column redirect_status smallint check (redirect_status in (301, 302, 307, 308));
+alter table urls add column interstitial boolean not null default false;
+drop index urls_generated_url;
+create unique index urls_generated_url on urls(domain, url)
+    where not custom and expires_at is null and redirect_status is null and not interstitial;
@@ -0,0 +1,2 @@
+-- 只保存管理 token 的哈希，之前创建的短链接没有 token，不能通过 API 修改和删除
+alter table urls add column token_hash text;
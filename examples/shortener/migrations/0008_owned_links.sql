@@ -0,0 +1,5 @@
+-- 有管理 token 的短链接属于创建它的人，不按 URL 去重，否则其他人拿到的同一个短链接可以被创建者改掉
+drop index urls_generated_url;
+create unique index urls_generated_url on urls(domain, url)
+    where not custom and expires_at is null and redirect_status is null and not interstitial
+    and token_hash is null;
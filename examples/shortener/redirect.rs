@@ -0,0 +1,114 @@
+/*
+重定向的方式
+    - 每个短链接可以选择 301、302、307 或者 308，没有选择时使用 `--default-redirect`，默认是临时的 302
+    - 永久重定向会被浏览器一直缓存，之后的点击不会再到达服务，统计不到也不能修改目标
+    - 中间页模式返回一个 HTML 页面，显示目标 URL，`--interstitial-secs` 秒之后自动跳转
+*/
+
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, LOCATION},
+        HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+};
+use clap::Args;
+
+use crate::{error::Errors, store::UrlRecord};
+
+const REDIRECT_STATUSES: &[u16] = &[301, 302, 307, 308];
+
+#[derive(Debug, Args)]
+pub struct RedirectArgs {
+    /// Status of redirects of links that do not choose one: 301, 302, 307 or 308
+    #[arg(long, default_value_t = 302, value_parser = parse_redirect_status)]
+    default_redirect: u16,
+    /// Seconds the interstitial page is shown before redirecting
+    #[arg(long, default_value_t = 5)]
+    interstitial_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct Redirector {
+    default: StatusCode,
+    interstitial_secs: u64,
+}
+
+impl Redirector {
+    pub fn new(args: &RedirectArgs) -> Self {
+        Self {
+            //启动时已经检查过
+            default: StatusCode::from_u16(args.default_redirect).unwrap(),
+            interstitial_secs: args.interstitial_secs,
+        }
+    }
+
+    pub fn respond(&self, record: &UrlRecord) -> Result<Response, Errors> {
+        //检查 URL 之前保存的短链接可能不是合法的头
+        let Ok(location) = HeaderValue::from_str(&record.url) else {
+            return Err(Errors::BrokenLink(record.id.clone()));
+        };
+        if record.interstitial {
+            let page = interstitial_page(&record.url, self.interstitial_secs);
+            let headers = [(CACHE_CONTROL, HeaderValue::from_static("no-store"))];
+            return Ok((headers, Html(page)).into_response());
+        }
+        let status = record
+            .redirect_status
+            .and_then(|status| StatusCode::from_u16(status as u16).ok())
+            .unwrap_or(self.default);
+        Ok((status, [(LOCATION, location)]).into_response())
+    }
+}
+
+/// 检查请求中的状态码，转换成存储中的类型
+pub fn validate_redirect_status(status: u16) -> Result<i16, Errors> {
+    if !REDIRECT_STATUSES.contains(&status) {
+        return Err(Errors::InvalidRedirect(status));
+    }
+    Ok(status as i16)
+}
+
+fn parse_redirect_status(value: &str) -> Result<u16, String> {
+    let status: u16 = value.parse().map_err(|e| format!("{e}"))?;
+    if !REDIRECT_STATUSES.contains(&status) {
+        return Err(format!("must be one of {REDIRECT_STATUSES:?}"));
+    }
+    Ok(status)
+}
+
+fn interstitial_page(url: &str, secs: u64) -> String {
+    let url = escape_html(url);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<meta http-equiv="refresh" content="{secs};url={url}">
+<title>Redirecting</title>
+</head>
+<body>
+<p>You are being redirected to</p>
+<p><a href="{url}" rel="noreferrer">{url}</a></p>
+<p>in {secs} seconds.</p>
+</body>
+</html>
+"#
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
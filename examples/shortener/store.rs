@@ -0,0 +1,746 @@
+/*
+短链接的存储
+    - UrlStore：插入、查找、修改、删除、列出短链接
+    - 短链接按域名分开，ID 只在同一个域名中唯一，默认域名是空字符串，见 domains.rs
+    - 永久的生成的 ID 在同一个域名中按 URL 去重，同一个 URL 只有一个永久的生成的 ID，但是可以有多个自定义别名和有过期时间的短链接
+    - 指定了过期时间、重定向状态码或者中间页的短链接，以及有管理 token 的短链接不按 URL 去重
+    - 过期的短链接在被清理之前仍然可以查到，由调用者判断是否过期
+    - 短链接的点击分批写入，删除短链接时一起删除，不存在的短链接的点击被丢弃
+    - postgres：urls 和 clicks 表，ID 序列用数据库的 sequence，表结构由 migrations/ 下的迁移创建和修改
+    - memory：两个 DashMap，进程退出后丢失，用于本地运行
+    - file：内存中的 DashMap 加上只追加的 JSON lines 日志，启动时重放日志
+    - 用 `--store` 选择，`shortener check` 对每一种存储运行同样的检查，见 check.rs
+*/
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::{
+    future::Future,
+    io::ErrorKind,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::{
+    clicks::{Bucket, BucketCount, Click, ClickStats, ReferrerCount},
+    error::Errors,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize, Deserialize)]
+pub struct UrlRecord {
+    #[sqlx(default)]
+    #[serde(default)]
+    pub domain: String,
+    #[sqlx(default)]
+    pub id: String,
+    #[sqlx(default)]
+    pub url: String,
+    /// 用户指定的别名，不是生成的 ID
+    #[sqlx(default)]
+    #[serde(default)]
+    pub custom: bool,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// 重定向的状态码，None 时使用服务的默认值，见 redirect.rs
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<i16>,
+    /// 先显示目标 URL 的中间页，再跳转
+    #[sqlx(default)]
+    #[serde(default)]
+    pub interstitial: bool,
+    /// 管理 token 的哈希，不保存 token 本身，None 时不能通过 API 修改和删除，见 tokens.rs
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_hash: Option<String>,
+}
+
+/// 插入的结果，ID 和 URL 都不能重复
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inserted {
+    Ok,
+    IdTaken,
+    /// URL 已经有永久的生成的 ID，返回原来的 ID，只有插入永久的生成的 ID 时会出现
+    UrlExists(String),
+}
+
+/// 修改的结果，修改之后按 URL 去重的短链接的 URL 也不能重复
+#[derive(Debug, PartialEq, Eq)]
+pub enum Updated {
+    Ok,
+    Missing,
+    UrlExists(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StoreKind {
+    Postgres,
+    Memory,
+    File,
+}
+
+/// 返回的 future 都是 Send 的，可以在 axum 的 handler 中使用
+pub trait UrlStore: Send + Sync + 'static {
+    fn insert(&self, record: &UrlRecord) -> impl Future<Output = Result<Inserted, Errors>> + Send;
+
+    fn get(
+        &self,
+        domain: &str,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<UrlRecord>, Errors>> + Send;
+
+    /// 查找 URL 在域名中永久的生成的 ID，不包括自定义别名和有过期时间的短链接
+    fn find_by_url(
+        &self,
+        domain: &str,
+        url: &str,
+    ) -> impl Future<Output = Result<Option<UrlRecord>, Errors>> + Send;
+
+    /// 按域名和 ID 修改短链接的 URL、过期时间和重定向方式，其他字段不变
+    fn update(&self, record: &UrlRecord) -> impl Future<Output = Result<Updated, Errors>> + Send;
+
+    /// 返回是否删除了短链接
+    fn delete(&self, domain: &str, id: &str) -> impl Future<Output = Result<bool, Errors>> + Send;
+
+    /// 所有域名的短链接，按域名和 ID 排序，从 `offset` 开始最多 `limit` 个
+    fn list(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<UrlRecord>, Errors>> + Send;
+
+    /// 删除最多 `limit` 个在 `now` 之前过期的短链接，返回删除的数量
+    fn purge_expired(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> impl Future<Output = Result<usize, Errors>> + Send;
+
+    /// 一次写入一批点击
+    fn record_clicks(&self, clicks: &[Click]) -> impl Future<Output = Result<(), Errors>> + Send;
+
+    /// 短链接的点击统计，最多返回 `top` 个来源页面
+    fn click_stats(
+        &self,
+        domain: &str,
+        id: &str,
+        bucket: Bucket,
+        top: usize,
+    ) -> impl Future<Output = Result<ClickStats, Errors>> + Send;
+
+    /// sequence 策略使用的递增序号，从 1 开始
+    fn next_sequence(&self) -> impl Future<Output = Result<u64, Errors>> + Send;
+}
+
+#[derive(Debug)]
+pub struct PgStore {
+    db: PgPool,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    by_id: DashMap<(String, String), UrlRecord>,
+    /// 域名和 URL 到永久的生成的 ID
+    by_url: DashMap<(String, String), String>,
+    /// 域名和短链接的 ID 到点击
+    clicks: DashMap<(String, String), Vec<Click>>,
+    sequence: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    memory: MemoryStore,
+    /// 写日志时持有，保证日志的顺序和内存中的修改一致
+    log: Mutex<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogEntry {
+    Insert(UrlRecord),
+    Update(UrlRecord),
+    Delete {
+        #[serde(default)]
+        domain: String,
+        id: String,
+    },
+    Clicks {
+        clicks: Vec<Click>,
+    },
+}
+
+impl UrlRecord {
+    /// 只有永久的、使用默认重定向方式的、没有主人的生成的 ID 按 URL 去重，
+    /// 有主人的短链接可以被修改，不能交给其他人
+    pub fn deduplicated(&self) -> bool {
+        !self.custom
+            && self.expires_at.is_none()
+            && self.redirect_status.is_none()
+            && !self.interstitial
+            && self.token_hash.is_none()
+    }
+
+    /// 除了 ID 以外都一样，重复创建同一个别名时不是冲突
+    pub fn same_target(&self, other: &UrlRecord) -> bool {
+        self.url == other.url
+            && self.expires_at == other.expires_at
+            && self.redirect_status == other.redirect_status
+            && self.interstitial == other.interstitial
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn key(&self) -> (String, String) {
+        (self.domain.clone(), self.id.clone())
+    }
+}
+
+impl PgStore {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        sqlx::migrate!("examples/shortener/migrations")
+            .run(&pool)
+            .await?;
+        Ok(Self { db: pool })
+    }
+}
+
+impl UrlStore for PgStore {
+    async fn insert(&self, record: &UrlRecord) -> Result<Inserted, Errors> {
+        let sql = "INSERT INTO urls (domain, id, url, custom, expires_at, redirect_status, interstitial, token_hash) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT DO NOTHING";
+        let ret = sqlx::query(sql)
+            .bind(&record.domain)
+            .bind(&record.id)
+            .bind(&record.url)
+            .bind(record.custom)
+            .bind(record.expires_at)
+            .bind(record.redirect_status)
+            .bind(record.interstitial)
+            .bind(&record.token_hash)
+            .execute(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        if ret.rows_affected() == 1 {
+            return Ok(Inserted::Ok);
+        }
+        if !record.deduplicated() {
+            return Ok(Inserted::IdTaken);
+        }
+        //冲突的可能是 ID 也可能是 URL
+        match self.find_by_url(&record.domain, &record.url).await? {
+            Some(existing) => Ok(Inserted::UrlExists(existing.id)),
+            None => Ok(Inserted::IdTaken),
+        }
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>, Errors> {
+        let sql =
+            "SELECT domain, id, url, custom, expires_at, redirect_status, interstitial, token_hash FROM urls \
+            WHERE domain = $1 AND id = $2";
+        let ret: Option<UrlRecord> = sqlx::query_as(sql)
+            .bind(domain)
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ret)
+    }
+
+    async fn find_by_url(&self, domain: &str, url: &str) -> Result<Option<UrlRecord>, Errors> {
+        let sql =
+            "SELECT domain, id, url, custom, expires_at, redirect_status, interstitial, token_hash FROM urls \
+            WHERE domain = $1 AND url = $2 AND NOT custom AND expires_at IS NULL \
+            AND redirect_status IS NULL AND NOT interstitial AND token_hash IS NULL";
+        let ret: Option<UrlRecord> = sqlx::query_as(sql)
+            .bind(domain)
+            .bind(url)
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ret)
+    }
+
+    async fn update(&self, record: &UrlRecord) -> Result<Updated, Errors> {
+        let sql =
+            "UPDATE urls SET url = $3, expires_at = $4, redirect_status = $5, interstitial = $6 \
+            WHERE domain = $1 AND id = $2";
+        let ret = sqlx::query(sql)
+            .bind(&record.domain)
+            .bind(&record.id)
+            .bind(&record.url)
+            .bind(record.expires_at)
+            .bind(record.redirect_status)
+            .bind(record.interstitial)
+            .execute(&self.db)
+            .await;
+        match ret {
+            Ok(ret) if ret.rows_affected() == 0 => Ok(Updated::Missing),
+            Ok(_) => Ok(Updated::Ok),
+            //只有按 URL 去重的唯一索引会冲突
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                match self.find_by_url(&record.domain, &record.url).await? {
+                    Some(existing) => Ok(Updated::UrlExists(existing.id)),
+                    None => Err(Errors::ExecuteError(
+                        sql.to_string(),
+                        sqlx::Error::Database(e),
+                    )),
+                }
+            }
+            Err(e) => Err(Errors::ExecuteError(sql.to_string(), e)),
+        }
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool, Errors> {
+        let sql = "DELETE FROM urls WHERE domain = $1 AND id = $2";
+        let ret = sqlx::query(sql)
+            .bind(domain)
+            .bind(id)
+            .execute(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<UrlRecord>, Errors> {
+        let sql = r#"SELECT domain, id, url, custom, expires_at, redirect_status, interstitial, token_hash FROM urls ORDER BY domain COLLATE "C", id COLLATE "C" OFFSET $1 LIMIT $2"#;
+        let ret: Vec<UrlRecord> = sqlx::query_as(sql)
+            .bind(offset as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ret)
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>, limit: usize) -> Result<usize, Errors> {
+        let sql =
+            "DELETE FROM urls WHERE (domain, id) IN (SELECT domain, id FROM urls WHERE expires_at <= $1 LIMIT $2)";
+        let ret = sqlx::query(sql)
+            .bind(now)
+            .bind(limit as i64)
+            .execute(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ret.rows_affected() as usize)
+    }
+
+    /// 过滤掉已经被删除的短链接的点击，否则整批都会违反外键约束
+    async fn record_clicks(&self, clicks: &[Click]) -> Result<(), Errors> {
+        let sql = "INSERT INTO clicks (domain, link_id, at, referrer, user_agent, ip_hash) \
+            SELECT c.* FROM UNNEST($1::varchar[], $2::varchar[], $3::timestamptz[], $4::text[], $5::text[], $6::text[]) \
+            AS c(domain, link_id, at, referrer, user_agent, ip_hash) \
+            WHERE EXISTS (SELECT 1 FROM urls WHERE urls.domain = c.domain AND urls.id = c.link_id)";
+        sqlx::query(sql)
+            .bind(clicks.iter().map(|c| c.domain.clone()).collect::<Vec<_>>())
+            .bind(clicks.iter().map(|c| c.id.clone()).collect::<Vec<_>>())
+            .bind(clicks.iter().map(|c| c.at).collect::<Vec<_>>())
+            .bind(
+                clicks
+                    .iter()
+                    .map(|c| c.referrer.clone())
+                    .collect::<Vec<_>>(),
+            )
+            .bind(
+                clicks
+                    .iter()
+                    .map(|c| c.user_agent.clone())
+                    .collect::<Vec<_>>(),
+            )
+            .bind(clicks.iter().map(|c| c.ip_hash.clone()).collect::<Vec<_>>())
+            .execute(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(())
+    }
+
+    async fn click_stats(
+        &self,
+        domain: &str,
+        id: &str,
+        bucket: Bucket,
+        top: usize,
+    ) -> Result<ClickStats, Errors> {
+        let sql = "SELECT date_trunc($3, at, 'UTC') AS start, count(*) FROM clicks \
+            WHERE domain = $1 AND link_id = $2 GROUP BY start ORDER BY start";
+        let histogram: Vec<(DateTime<Utc>, i64)> = sqlx::query_as(sql)
+            .bind(domain)
+            .bind(id)
+            .bind(bucket.as_str())
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        let sql = r#"SELECT referrer, count(*) AS clicks FROM clicks
+            WHERE domain = $1 AND link_id = $2 AND referrer IS NOT NULL
+            GROUP BY referrer ORDER BY clicks DESC, referrer COLLATE "C" LIMIT $3"#;
+        let referrers: Vec<(String, i64)> = sqlx::query_as(sql)
+            .bind(domain)
+            .bind(id)
+            .bind(top as i64)
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(ClickStats {
+            total: histogram.iter().map(|(_, clicks)| *clicks as u64).sum(),
+            histogram: histogram
+                .into_iter()
+                .map(|(start, clicks)| BucketCount {
+                    start,
+                    clicks: clicks as u64,
+                })
+                .collect(),
+            top_referrers: referrers
+                .into_iter()
+                .map(|(referrer, clicks)| ReferrerCount {
+                    referrer,
+                    clicks: clicks as u64,
+                })
+                .collect(),
+        })
+    }
+
+    async fn next_sequence(&self) -> Result<u64, Errors> {
+        let sql = "SELECT nextval('url_id_seq')";
+        let n: i64 = sqlx::query_scalar(sql)
+            .fetch_one(&self.db)
+            .await
+            .map_err(|e| Errors::ExecuteError(sql.to_string(), e))?;
+        Ok(n as u64)
+    }
+}
+
+impl MemoryStore {
+    fn insert_sync(&self, record: &UrlRecord) -> Inserted {
+        //永久的生成的 ID 先占用 URL 再占用 ID，ID 被占用时释放 URL
+        if record.deduplicated() {
+            match self
+                .by_url
+                .entry((record.domain.clone(), record.url.clone()))
+            {
+                Entry::Occupied(entry) => return Inserted::UrlExists(entry.get().clone()),
+                Entry::Vacant(entry) => {
+                    entry.insert(record.id.clone());
+                }
+            }
+        }
+        match self.by_id.entry(record.key()) {
+            Entry::Occupied(_) => {
+                self.unlink_url(record);
+                Inserted::IdTaken
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(record.clone());
+                Inserted::Ok
+            }
+        }
+    }
+
+    /// 先占用新的 URL，再修改短链接，最后释放旧的 URL，不同时持有两个 map 的锁
+    fn update_sync(&self, record: &UrlRecord) -> Updated {
+        let key = record.key();
+        let Some(old) = self.by_id.get(&key).map(|old| old.clone()) else {
+            return Updated::Missing;
+        };
+        let url_changed = !old.deduplicated() || old.url != record.url;
+        if record.deduplicated() && url_changed {
+            match self
+                .by_url
+                .entry((record.domain.clone(), record.url.clone()))
+            {
+                Entry::Occupied(entry) => return Updated::UrlExists(entry.get().clone()),
+                Entry::Vacant(entry) => {
+                    entry.insert(record.id.clone());
+                }
+            }
+        }
+        let Some(mut current) = self.by_id.get_mut(&key) else {
+            self.unlink_url(record);
+            return Updated::Missing;
+        };
+        current.url = record.url.clone();
+        current.expires_at = record.expires_at;
+        current.redirect_status = record.redirect_status;
+        current.interstitial = record.interstitial;
+        drop(current);
+        if old.deduplicated() && (!record.deduplicated() || old.url != record.url) {
+            self.unlink_url(&old);
+        }
+        Updated::Ok
+    }
+
+    fn delete_sync(&self, domain: &str, id: &str) -> bool {
+        let key = (domain.to_string(), id.to_string());
+        let Some((_, record)) = self.by_id.remove(&key) else {
+            return false;
+        };
+        self.unlink_url(&record);
+        self.clicks.remove(&key);
+        true
+    }
+
+    /// 只保留还存在的短链接的点击
+    fn existing_clicks(&self, clicks: &[Click]) -> Vec<Click> {
+        clicks
+            .iter()
+            .filter(|click| self.by_id.contains_key(&click.key()))
+            .cloned()
+            .collect()
+    }
+
+    fn record_clicks_sync(&self, clicks: Vec<Click>) {
+        for click in clicks {
+            self.clicks.entry(click.key()).or_default().push(click);
+        }
+    }
+
+    fn expired(&self, now: DateTime<Utc>, limit: usize) -> Vec<(String, String)> {
+        self.by_id
+            .iter()
+            .filter(|record| record.is_expired(now))
+            .take(limit)
+            .map(|record| record.value().key())
+            .collect()
+    }
+
+    fn unlink_url(&self, record: &UrlRecord) {
+        if record.deduplicated() {
+            let key = (record.domain.clone(), record.url.clone());
+            self.by_url.remove_if(&key, |_, id| *id == record.id);
+        }
+    }
+}
+
+impl UrlStore for MemoryStore {
+    async fn insert(&self, record: &UrlRecord) -> Result<Inserted, Errors> {
+        Ok(self.insert_sync(record))
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>, Errors> {
+        let key = (domain.to_string(), id.to_string());
+        Ok(self.by_id.get(&key).map(|record| record.clone()))
+    }
+
+    async fn find_by_url(&self, domain: &str, url: &str) -> Result<Option<UrlRecord>, Errors> {
+        let key = (domain.to_string(), url.to_string());
+        let Some(id) = self.by_url.get(&key).map(|id| id.clone()) else {
+            return Ok(None);
+        };
+        self.get(domain, &id).await
+    }
+
+    async fn update(&self, record: &UrlRecord) -> Result<Updated, Errors> {
+        Ok(self.update_sync(record))
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool, Errors> {
+        Ok(self.delete_sync(domain, id))
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<UrlRecord>, Errors> {
+        let mut records: Vec<UrlRecord> = self
+            .by_id
+            .iter()
+            .map(|record| record.value().clone())
+            .collect();
+        records.sort_by(|a, b| (&a.domain, &a.id).cmp(&(&b.domain, &b.id)));
+        Ok(records.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>, limit: usize) -> Result<usize, Errors> {
+        let keys = self.expired(now, limit);
+        Ok(keys
+            .iter()
+            .filter(|(domain, id)| self.delete_sync(domain, id))
+            .count())
+    }
+
+    async fn record_clicks(&self, clicks: &[Click]) -> Result<(), Errors> {
+        self.record_clicks_sync(self.existing_clicks(clicks));
+        Ok(())
+    }
+
+    async fn click_stats(
+        &self,
+        domain: &str,
+        id: &str,
+        bucket: Bucket,
+        top: usize,
+    ) -> Result<ClickStats, Errors> {
+        let key = (domain.to_string(), id.to_string());
+        let Some(clicks) = self.clicks.get(&key) else {
+            return Ok(ClickStats::default());
+        };
+        Ok(ClickStats::from_clicks(clicks.iter(), bucket, top))
+    }
+
+    async fn next_sequence(&self) -> Result<u64, Errors> {
+        Ok(self.sequence.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+impl FileStore {
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let memory = MemoryStore::default();
+        //没有单独保存序号，从插入过的短链接数开始，重复的序号由生成 ID 时的重试处理
+        let mut inserts = 0;
+        for (n, line) in content.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(LogEntry::Insert(record)) => {
+                    memory.insert_sync(&record);
+                    inserts += 1;
+                }
+                Ok(LogEntry::Update(record)) => {
+                    memory.update_sync(&record);
+                }
+                Ok(LogEntry::Delete { domain, id }) => {
+                    memory.delete_sync(&domain, &id);
+                }
+                Ok(LogEntry::Clicks { clicks }) => memory.record_clicks_sync(clicks),
+                Err(e) => warn!("Skip broken entry at {}:{}: {e}", path.display(), n + 1),
+            }
+        }
+        memory.sequence.store(inserts, Ordering::Relaxed);
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            memory,
+            log: Mutex::new(log),
+        })
+    }
+
+    async fn append(log: &mut File, entry: &LogEntry) -> Result<(), Errors> {
+        let mut line =
+            serde_json::to_string(entry).map_err(|e| Errors::StorageError(e.to_string()))?;
+        line.push('\n');
+        log.write_all(line.as_bytes())
+            .await
+            .map_err(|e| Errors::StorageError(e.to_string()))?;
+        log.flush()
+            .await
+            .map_err(|e| Errors::StorageError(e.to_string()))
+    }
+}
+
+impl UrlStore for FileStore {
+    /// 先修改内存再写日志，写失败时撤销内存中的修改
+    async fn insert(&self, record: &UrlRecord) -> Result<Inserted, Errors> {
+        let mut log = self.log.lock().await;
+        let inserted = self.memory.insert_sync(record);
+        if inserted != Inserted::Ok {
+            return Ok(inserted);
+        }
+        if let Err(e) = Self::append(&mut log, &LogEntry::Insert(record.clone())).await {
+            self.memory.delete_sync(&record.domain, &record.id);
+            return Err(e);
+        }
+        Ok(inserted)
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>, Errors> {
+        self.memory.get(domain, id).await
+    }
+
+    async fn find_by_url(&self, domain: &str, url: &str) -> Result<Option<UrlRecord>, Errors> {
+        self.memory.find_by_url(domain, url).await
+    }
+
+    /// 写日志失败时改回原来的短链接
+    async fn update(&self, record: &UrlRecord) -> Result<Updated, Errors> {
+        let mut log = self.log.lock().await;
+        let Some(old) = self.memory.get(&record.domain, &record.id).await? else {
+            return Ok(Updated::Missing);
+        };
+        let updated = self.memory.update_sync(record);
+        if updated != Updated::Ok {
+            return Ok(updated);
+        }
+        if let Err(e) = Self::append(&mut log, &LogEntry::Update(record.clone())).await {
+            self.memory.update_sync(&old);
+            return Err(e);
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool, Errors> {
+        let mut log = self.log.lock().await;
+        if self.memory.get(domain, id).await?.is_none() {
+            return Ok(false);
+        }
+        let entry = LogEntry::Delete {
+            domain: domain.to_string(),
+            id: id.to_string(),
+        };
+        Self::append(&mut log, &entry).await?;
+        self.memory.delete_sync(domain, id);
+        Ok(true)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Vec<UrlRecord>, Errors> {
+        self.memory.list(offset, limit).await
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>, limit: usize) -> Result<usize, Errors> {
+        let mut purged = 0;
+        for (domain, id) in self.memory.expired(now, limit) {
+            if self.delete(&domain, &id).await? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn record_clicks(&self, clicks: &[Click]) -> Result<(), Errors> {
+        let mut log = self.log.lock().await;
+        let clicks = self.memory.existing_clicks(clicks);
+        if clicks.is_empty() {
+            return Ok(());
+        }
+        Self::append(
+            &mut log,
+            &LogEntry::Clicks {
+                clicks: clicks.clone(),
+            },
+        )
+        .await?;
+        self.memory.record_clicks_sync(clicks);
+        Ok(())
+    }
+
+    async fn click_stats(
+        &self,
+        domain: &str,
+        id: &str,
+        bucket: Bucket,
+        top: usize,
+    ) -> Result<ClickStats, Errors> {
+        self.memory.click_stats(domain, id, bucket, top).await
+    }
+
+    async fn next_sequence(&self) -> Result<u64, Errors> {
+        self.memory.next_sequence().await
+    }
+}
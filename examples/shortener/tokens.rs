@@ -0,0 +1,47 @@
+/*
+短链接的管理 token
+    - POST / 新建短链接时生成一个随机 token 返回给创建者，存储中只保存它的 blake3 哈希
+    - PATCH /:id 和 DELETE /:id 需要 `Authorization: Bearer <token>`
+    - 有 token 的短链接不按 URL 去重，每次缩短都新建一个，不会拿到别人可以修改的短链接
+    - 重复创建同一个别名时不返回 token，短链接只属于创建它的人
+*/
+
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+
+use crate::error::Errors;
+
+/// 随机 token 的长度，nanoid 的字符集每个字符 6 位，一共 192 位
+const TOKEN_LEN: usize = 32;
+
+/// 返回 token 和它的哈希
+pub fn generate() -> (String, String) {
+    let token = nanoid::nanoid!(TOKEN_LEN);
+    let hash = hash(&token);
+    (token, hash)
+}
+
+/// 检查请求中的 token 是不是短链接的 token，没有 token 的短链接不能管理
+pub fn authorize(headers: &HeaderMap, id: &str, token_hash: Option<&str>) -> Result<(), Errors> {
+    let Some(token) = bearer(headers) else {
+        return Err(Errors::Unauthorized(
+            "a Bearer token is required".to_string(),
+        ));
+    };
+    let expected = token_hash.and_then(|hash| blake3::Hash::from_hex(hash).ok());
+    //blake3::Hash 的比较是常数时间的
+    match expected {
+        Some(expected) if expected == blake3::hash(token.as_bytes()) => Ok(()),
+        _ => Err(Errors::Forbidden(id.to_string())),
+    }
+}
+
+fn hash(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
@@ -0,0 +1,276 @@
+/*
+提交的 URL 的检查和规范化
+    - 只接受 `--allowed-schemes` 中的协议（默认 http 和 https），`javascript:`、`data:` 之类的 URL 总是拒绝
+    - 规范化：协议和域名小写，国际化域名转成 punycode，去掉默认端口，去掉路径中的 `.` 和 `..`
+    - 指向短链接服务自己（监听的地址和每一个 `--base-url`）的 URL 会形成重定向循环，拒绝
+    - `--blocklist` 文件中每行一个域名，`#` 开头的是注释，屏蔽这个域名和它的子域名
+*/
+
+use anyhow::Context;
+use clap::Args;
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
+use url::{Host, Url};
+
+use crate::error::Errors;
+
+/// 超过这个长度的 URL 在很多浏览器和代理中不能用
+const MAX_URL_LEN: usize = 2048;
+/// 就算出现在 `--allowed-schemes` 中也不接受，可以在打开的页面中执行代码
+const UNSAFE_SCHEMES: &[&str] = &["javascript", "data", "vbscript", "file", "blob"];
+
+#[derive(Debug, Args)]
+pub struct UrlArgs {
+    /// Schemes of URLs that can be shortened
+    #[arg(long, value_delimiter = ',', default_value = "http,https")]
+    allowed_schemes: Vec<String>,
+    /// File of blocked domains, one per line
+    #[arg(long)]
+    blocklist: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct UrlPolicy {
+    schemes: BTreeSet<String>,
+    /// 规范化之后的域名
+    blocked: BTreeSet<String>,
+    /// 短链接服务自己的 `host:port`
+    own_origins: BTreeSet<String>,
+}
+
+impl UrlPolicy {
+    /// `listen` 是服务监听的地址，`public` 是对外的 `host:port`，用于发现重定向循环
+    pub fn load(args: &UrlArgs, listen: SocketAddr, public: Vec<String>) -> anyhow::Result<Self> {
+        let mut schemes = BTreeSet::new();
+        for scheme in &args.allowed_schemes {
+            let scheme = scheme.trim().to_ascii_lowercase();
+            anyhow::ensure!(
+                !UNSAFE_SCHEMES.contains(&scheme.as_str()),
+                "--allowed-schemes can not contain {scheme}"
+            );
+            schemes.insert(scheme);
+        }
+        let mut blocked = BTreeSet::new();
+        if let Some(path) = &args.blocklist {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("can not read blocklist {}", path.display()))?;
+            for (n, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let domain = normalize_domain(line).with_context(|| {
+                    format!("invalid domain {line:?} at {}:{}", path.display(), n + 1)
+                })?;
+                blocked.insert(domain);
+            }
+        }
+        let mut own_origins = own_origins(listen);
+        own_origins.extend(public);
+        Ok(Self {
+            schemes,
+            blocked,
+            own_origins,
+        })
+    }
+
+    pub fn blocked_domains(&self) -> usize {
+        self.blocked.len()
+    }
+
+    /// 返回规范化之后的 URL
+    pub fn normalize(&self, raw: &str) -> Result<String, Errors> {
+        let raw = raw.trim();
+        if raw.len() > MAX_URL_LEN {
+            return Err(Errors::InvalidUrl(format!(
+                "url is longer than {MAX_URL_LEN} bytes"
+            )));
+        }
+        let url = Url::parse(raw).map_err(|e| Errors::InvalidUrl(format!("{raw:?}: {e}")))?;
+        let scheme = url.scheme();
+        if UNSAFE_SCHEMES.contains(&scheme) || !self.schemes.contains(scheme) {
+            return Err(Errors::InvalidUrl(format!(
+                "scheme {scheme} is not allowed"
+            )));
+        }
+        let Some(host) = url.host() else {
+            return Err(Errors::InvalidUrl(format!("{raw:?} has no host")));
+        };
+        let host = host.to_string();
+        let host = host.trim_end_matches('.');
+        if self.is_blocked(host) {
+            return Err(Errors::BlockedUrl(host.to_string()));
+        }
+        if let Some(port) = url.port_or_known_default() {
+            if self.own_origins.contains(&format!("{host}:{port}")) {
+                return Err(Errors::InvalidUrl(
+                    "url points back to the shortener".to_string(),
+                ));
+            }
+        }
+        //Url 的序列化已经是规范化的形式，只包含 ASCII 字符，可以直接放进 Location 头
+        Ok(url.into())
+    }
+
+    /// 域名和它的每一级父域名
+    fn is_blocked(&self, host: &str) -> bool {
+        let mut domain = host;
+        loop {
+            if self.blocked.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// 和 URL 中的域名用同样的方式规范化，IP 地址原样保留
+fn normalize_domain(domain: &str) -> anyhow::Result<String> {
+    let host = Host::parse(domain.trim_end_matches('.'))?;
+    Ok(host.to_string())
+}
+
+/// 监听回环地址或者所有地址时，`localhost` 和其他回环地址也指向自己
+fn own_origins(listen: SocketAddr) -> BTreeSet<String> {
+    let port = listen.port();
+    let ip = listen.ip();
+    let mut hosts = vec![ip_host(ip)];
+    if ip.is_loopback() || ip.is_unspecified() {
+        hosts.push(ip_host(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        hosts.push(ip_host(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        hosts.push("localhost".to_string());
+    }
+    hosts
+        .into_iter()
+        .map(|host| format!("{host}:{port}"))
+        .collect()
+}
+
+/// 和 `Url::host` 的格式一样，IPv6 地址带方括号
+fn ip_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const LISTEN: &str = "127.0.0.1:9876";
+
+    fn policy(schemes: &[&str], blocklist: &[&str]) -> UrlPolicy {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# blocked domains").unwrap();
+        for domain in blocklist {
+            writeln!(file, "{domain}").unwrap();
+        }
+        let args = UrlArgs {
+            allowed_schemes: schemes.iter().map(|scheme| scheme.to_string()).collect(),
+            blocklist: Some(file.path().to_path_buf()),
+        };
+        let public = vec!["sho.rt:443".to_string()];
+        UrlPolicy::load(&args, LISTEN.parse().unwrap(), public).unwrap()
+    }
+
+    fn default_policy() -> UrlPolicy {
+        policy(&["http", "https"], &["evil.example", "Bücher.example."])
+    }
+
+    #[test]
+    fn only_allowed_schemes_are_accepted() {
+        let web = default_policy();
+        assert!(web.normalize("https://example.com/").is_ok());
+        assert!(matches!(
+            web.normalize("ftp://example.com/file"),
+            Err(Errors::InvalidUrl(_))
+        ));
+        let ftp_only = policy(&["ftp"], &[]);
+        assert!(ftp_only.normalize("ftp://example.com/file").is_ok());
+        assert!(ftp_only.normalize("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn script_and_data_urls_are_always_rejected() {
+        let policy = default_policy();
+        for url in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox(1)",
+        ] {
+            assert!(policy.normalize(url).is_err(), "{url} should be rejected");
+        }
+        let args = UrlArgs {
+            allowed_schemes: vec!["https".to_string(), "javascript".to_string()],
+            blocklist: None,
+        };
+        assert!(UrlPolicy::load(&args, LISTEN.parse().unwrap(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn urls_are_normalized() {
+        let policy = default_policy();
+        let normalize = |url| policy.normalize(url).unwrap();
+        assert_eq!(
+            normalize("HTTPS://Example.COM:443/a/./b/../c?q=1#top"),
+            "https://example.com/a/c?q=1#top"
+        );
+        assert_eq!(normalize("http://example.com:80"), "http://example.com/");
+        assert_eq!(
+            normalize("http://example.com:8080/"),
+            "http://example.com:8080/"
+        );
+        assert_eq!(
+            normalize("https://münchen.example/straße"),
+            "https://xn--mnchen-3ya.example/stra%C3%9Fe"
+        );
+    }
+
+    #[test]
+    fn links_back_to_the_shortener_are_rejected() {
+        let policy = default_policy();
+        for url in [
+            "http://127.0.0.1:9876/abc",
+            "http://localhost:9876/abc",
+            "http://[::1]:9876/abc",
+            "https://sho.rt/abc",
+            "https://SHO.RT:443/abc",
+        ] {
+            assert!(
+                matches!(policy.normalize(url), Err(Errors::InvalidUrl(_))),
+                "{url} should be rejected"
+            );
+        }
+        assert!(policy.normalize("http://127.0.0.1:9877/abc").is_ok());
+        assert!(policy.normalize("http://sho.rt/abc").is_ok());
+    }
+
+    #[test]
+    fn blocked_domains_include_subdomains() {
+        let policy = default_policy();
+        assert_eq!(policy.blocked_domains(), 2);
+        for url in [
+            "https://evil.example/",
+            "https://EVIL.example./",
+            "https://a.b.evil.example/x",
+            "https://bücher.example/",
+            "https://shop.xn--bcher-kva.example/",
+        ] {
+            assert!(
+                matches!(policy.normalize(url), Err(Errors::BlockedUrl(_))),
+                "{url} should be blocked"
+            );
+        }
+        assert!(policy.normalize("https://notevil.example/").is_ok());
+        assert!(policy.normalize("https://evil.example.com/").is_ok());
+    }
+}
@@ -1,3 +1,7 @@
+#[path = "common/chat_ui.rs"]
+mod chat_ui;
+
+use chat_ui::ChatView;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
@@ -10,13 +14,10 @@ use std::{error::Error, io};
 use tokio::{sync::Mutex, time::Duration};
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Modifier, Style},
-    text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    text::{Span, Spans},
+    widgets::ListItem,
     Frame, Terminal,
 };
-use unicode_width::UnicodeWidthStr;
 
 struct ChatApp {
     input: String,
@@ -86,43 +87,15 @@ impl ChatApp {
     }
 
     fn ui<B: Backend>(&mut self, frame: &mut Frame<B>, messages: Vec<ListItem>) {
-        let chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .margin(2)
-            .constraints(
-                [
-                    Constraint::Length(1),
-                    Constraint::Length(3),
-                    Constraint::Min(1),
-                ]
-                .as_ref(),
-            )
-            .split(frame.size());
-
-        let text = Text::from(Spans::from(vec![
-            Span::raw("Press "),
-            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to send the message, "),
-            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to quit"),
-        ]));
-        let help_message = Paragraph::new(text);
-        frame.render_widget(help_message, chunks[0]);
-
-        let input = Paragraph::new(self.input.as_ref())
-            .style(Style::default().fg(Color::Yellow))
-            .block(Block::default().borders(Borders::ALL).title("Input"));
-        frame.render_widget(input, chunks[1]);
-        frame.set_cursor(
-            // Put cursor past the end of the input text
-            chunks[1].x + self.input.width() as u16 + 1,
-            // Move one line down, from the border to the input line
-            chunks[1].y + 1,
-        );
-
-        let messages =
-            List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-        frame.render_widget(messages, chunks[2]);
+        let view = ChatView {
+            hints: &[("Enter", "send the message"), ("Esc", "quit")],
+            input: &self.input,
+            title: "Messages",
+            messages,
+            scroll: 0,
+            users: None,
+        };
+        chat_ui::draw(frame, view);
     }
 }
 